@@ -1,23 +1,67 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fs::{self, Permissions},
 };
 
 use askama::Template;
 
-use crate::{config::Config, services::BEAM_NETWORKS, utils::filters};
+use crate::{
+    config::Config,
+    enroll,
+    services::{BEAM_NETWORKS, ServiceGraph, compose::ComposeFile},
+    utils::filters,
+};
 
 #[derive(Debug, Template)]
 #[template(path = "bridgehead")]
 pub struct Bridgehead {
     beam_networks: BTreeSet<String>,
+    /// Why networks are not enrolled keyed by broker id
+    unenrolled: BTreeMap<String, String>,
+    /// Compose services that need an unenrolled network
+    refused: BTreeSet<String>,
+    /// Compose services that can be started regardless of the enrollment
+    startable: BTreeSet<String>,
     conf: &'static Config,
 }
 
 impl Bridgehead {
+    /// Script for a config whose services have not been generated yet
     pub fn new(conf: &'static Config) -> Self {
         Self {
-            beam_networks: BEAM_NETWORKS.with_borrow(|nets| nets.clone()),
+            beam_networks: BTreeSet::new(),
+            unenrolled: BTreeMap::new(),
+            refused: BTreeSet::new(),
+            startable: BTreeSet::new(),
+            conf,
+        }
+    }
+
+    /// Script for the generated compose `files` which refuses to start the services of unenrolled beam networks
+    pub fn for_services(
+        conf: &'static Config,
+        graph: &ServiceGraph,
+        files: &BTreeMap<String, ComposeFile>,
+    ) -> Self {
+        let proxies = BEAM_NETWORKS.with_borrow(|nets| nets.clone());
+        let beam_networks: BTreeSet<_> = proxies.keys().cloned().collect();
+        let unenrolled = enroll::enrollment_problems(conf, &beam_networks);
+        let refused_files: BTreeSet<_> = unenrolled
+            .keys()
+            .flat_map(|broker_id| graph.dependents(&proxies[broker_id]))
+            .collect();
+        let (refused, startable) = files
+            .iter()
+            .flat_map(|(name, file)| {
+                let refused = refused_files.contains(name);
+                file.services.keys().map(move |service| (service, refused))
+            })
+            .partition::<Vec<_>, _>(|(_, refused)| *refused);
+        Self {
+            beam_networks,
+            unenrolled,
+            refused: refused.into_iter().map(|(s, _)| s.clone()).collect(),
+            startable: startable.into_iter().map(|(s, _)| s.clone()).collect(),
             conf,
         }
     }
@@ -0,0 +1,484 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rcgen::{CertificateParams, KeyPair, PublicKeyData};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use url::Host;
+use x509_parser::{
+    der_parser, extensions::GeneralName, pem::Pem, prelude::X509Certificate, public_key::PublicKey,
+};
+
+use crate::config::{Config, make_private, write_private};
+
+/// Validity of the self-signed certificates generated by rusthead
+const SELF_SIGNED_VALIDITY_DAYS: i64 = 365;
+
+/// Subject common name rcgen uses by default which identifies certificates generated by rusthead
+const RCGEN_DEFAULT_CN: &str = "rcgen self signed cert";
+
+/// Generation metadata of a self-signed certificate so it can be renewed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfSignedCert {
+    pub sans: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub not_after: OffsetDateTime,
+}
+
+/// Generates a self-signed certificate for `sans` unless one generated by rusthead exists that
+/// matches the SANs and does not expire within `cert_expiry_warning_days`. Certificates not generated by rusthead are left untouched.
+/// Returns whether a certificate was generated.
+pub fn ensure_self_signed(
+    conf: &Config,
+    cert_path: &Path,
+    key_path: &Path,
+    sans: Vec<String>,
+) -> anyhow::Result<bool> {
+    let id = cert_path
+        .strip_prefix(&conf.path)
+        .unwrap_or(cert_path)
+        .display()
+        .to_string();
+    let mut local_conf = conf.local_conf.borrow_mut();
+    let tracked = match local_conf.self_signed_certs.get(&id) {
+        Some(tracked) => Some(tracked.clone()),
+        None if cert_path.exists() => {
+            let adopted = adopt_legacy(cert_path)?;
+            if adopted.is_some() && key_path.exists() {
+                // Older versions wrote the key with the default umask
+                make_private(key_path)?;
+            }
+            adopted
+        }
+        None => None,
+    };
+    let renew_reason = match (&tracked, cert_path.exists(), key_path.exists()) {
+        (_, false, false) => "it does not exist".to_string(),
+        (Some(_), true, false) | (Some(_), false, true) => "its key pair is incomplete".to_string(),
+        (Some(tracked), true, true) if tracked.sans != sans => {
+            format!("it was issued for {:?} instead of {sans:?}", tracked.sans)
+        }
+        (Some(tracked), true, true)
+            if tracked.not_after - OffsetDateTime::now_utc()
+                < Duration::days(conf.cert_expiry_warning_days.into()) =>
+        {
+            format!("it expires on {}", tracked.not_after)
+        }
+        (Some(_), true, true) | (None, true, true) => return Ok(false),
+        (None, true, false) => {
+            anyhow::bail!(
+                "{} exists but {} does not",
+                cert_path.display(),
+                key_path.display()
+            )
+        }
+        (None, false, true) => {
+            anyhow::bail!(
+                "{} exists but {} does not",
+                key_path.display(),
+                cert_path.display()
+            )
+        }
+    };
+    eprintln!("Generating self-signed certificate {id} because {renew_reason}");
+    let mut params = CertificateParams::new(sans.clone())
+        .context("Failed to generate self-signed certificate")?;
+    let not_after = OffsetDateTime::now_utc() + Duration::days(SELF_SIGNED_VALIDITY_DAYS);
+    params.not_after = not_after;
+    let key = KeyPair::generate().context("Failed to generate key pair")?;
+    let cert = params
+        .self_signed(&key)
+        .context("Failed to generate self-signed certificate")?;
+    fs::write(cert_path, cert.pem())
+        .with_context(|| format!("Failed to write {}", cert_path.display()))?;
+    write_private(key_path, &key.serialize_pem())?;
+    local_conf
+        .self_signed_certs
+        .insert(id, SelfSignedCert { sans, not_after });
+    Ok(true)
+}
+
+/// Returns the metadata of a certificate generated by a version that did not track it yet
+fn adopt_legacy(cert_path: &Path) -> anyhow::Result<Option<SelfSignedCert>> {
+    let pem = read_pem(cert_path)?;
+    let cert = pem
+        .parse_x509()
+        .with_context(|| format!("Failed to parse certificate {}", cert_path.display()))?;
+    let is_legacy = cert
+        .subject()
+        .iter_common_name()
+        .any(|cn| cn.as_str() == Ok(RCGEN_DEFAULT_CN));
+    if !is_legacy {
+        return Ok(None);
+    }
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::IPAddress([a, b, c, d]) => {
+                    Some(std::net::Ipv4Addr::new(*a, *b, *c, *d).to_string())
+                }
+                GeneralName::IPAddress(ip) => <[u8; 16]>::try_from(*ip)
+                    .ok()
+                    .map(|ip| std::net::Ipv6Addr::from(ip).to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(Some(SelfSignedCert {
+        sans,
+        not_after: cert.validity().not_after.to_datetime(),
+    }))
+}
+
+/// Fails if the certificate at `cert_path` is not currently valid or does not belong to the key at `key_path`.
+/// Warns if it expires within `warning_days` or does not cover `hostname`.
+pub fn check_cert_pair(
+    cert_path: &Path,
+    key_path: &Path,
+    hostname: &Host,
+    warning_days: u32,
+) -> anyhow::Result<()> {
+    let cert_pem = read_pem(cert_path)?;
+    let cert = cert_pem
+        .parse_x509()
+        .with_context(|| format!("Failed to parse certificate {}", cert_path.display()))?;
+    check_validity(&cert, cert_path, warning_days)?;
+    if !covers(&cert, hostname) {
+        eprintln!(
+            "Warning: {} is not valid for {hostname}",
+            cert_path.display()
+        );
+    }
+    let key = read_pem(key_path)?;
+    match key_matches(&cert, &key) {
+        Some(true) => {}
+        Some(false) => anyhow::bail!(
+            "{} does not match the certificate {}",
+            key_path.display(),
+            cert_path.display()
+        ),
+        None => eprintln!(
+            "Warning: Unsupported key format {} in {}. Skipping check if it matches {}",
+            key.label,
+            key_path.display(),
+            cert_path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Fails if the file does not contain a PEM encoded certificate that is currently valid.
+/// Warns if it expires within `warning_days`.
+pub fn check_ca_cert(path: &Path, warning_days: u32) -> anyhow::Result<()> {
+    let pem = read_pem(path)?;
+    let cert = pem
+        .parse_x509()
+        .with_context(|| format!("Failed to parse certificate {}", path.display()))?;
+    check_validity(&cert, path, warning_days)
+}
+
+/// Fails if the file does not contain a PEM encoded private key
+pub fn check_private_key(path: &Path) -> anyhow::Result<()> {
+    let key = read_pem(path)?;
+    anyhow::ensure!(
+        key.label.ends_with("PRIVATE KEY") && !key.contents.is_empty(),
+        "{} does not contain a private key but {}",
+        path.display(),
+        key.label
+    );
+    Ok(())
+}
+
+/// Reads the first PEM block of a file
+fn read_pem(path: &Path) -> anyhow::Result<Pem> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Pem::iter_from_buffer(&content)
+        .next()
+        .with_context(|| format!("{} is empty", path.display()))?
+        .with_context(|| format!("{} is not PEM encoded", path.display()))
+}
+
+fn check_validity(cert: &X509Certificate, path: &Path, warning_days: u32) -> anyhow::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let validity = cert.validity();
+    anyhow::ensure!(
+        validity.not_after.timestamp() > now,
+        "{} expired on {}",
+        path.display(),
+        validity.not_after
+    );
+    anyhow::ensure!(
+        validity.not_before.timestamp() <= now,
+        "{} is not valid before {}",
+        path.display(),
+        validity.not_before
+    );
+    let days_left = (validity.not_after.timestamp() - now) / (24 * 60 * 60);
+    if days_left < warning_days.into() {
+        eprintln!(
+            "Warning: {} expires in {days_left} days on {}",
+            path.display(),
+            validity.not_after
+        );
+    }
+    Ok(())
+}
+
+/// Whether a SAN (or the CN if there are none) matches the host
+fn covers(cert: &X509Certificate, host: &Host) -> bool {
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => &san.value.general_names,
+        _ => {
+            return cert
+                .subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .any(|cn| matches_domain(cn, host));
+        }
+    };
+    names.iter().any(|name| match (name, host) {
+        (GeneralName::DNSName(name), host) => matches_domain(name, host),
+        (GeneralName::IPAddress(ip), Host::Ipv4(host)) => *ip == host.octets(),
+        (GeneralName::IPAddress(ip), Host::Ipv6(host)) => *ip == host.octets(),
+        _ => false,
+    })
+}
+
+fn matches_domain(pattern: &str, host: &Host) -> bool {
+    let host = host.to_string();
+    match pattern.strip_prefix("*.") {
+        // Wildcards only cover a single label
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(_, host_parent)| host_parent.eq_ignore_ascii_case(parent)),
+        None => pattern.eq_ignore_ascii_case(&host),
+    }
+}
+
+/// Returns `None` if the key format is not supported
+fn key_matches(cert: &X509Certificate, key: &Pem) -> Option<bool> {
+    if key.label == "RSA PRIVATE KEY" {
+        // PKCS#1 keys start with the version followed by the modulus
+        let Ok(PublicKey::RSA(public)) = cert.public_key().parsed() else {
+            return Some(false);
+        };
+        let (_, private) = der_parser::parse_der(&key.contents).ok()?;
+        let modulus = private.as_sequence().ok()?.get(1)?.as_slice().ok()?;
+        let trim = |b: &'_ [u8]| {
+            b.iter()
+                .skip_while(|&&b| b == 0)
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        return Some(trim(modulus) == trim(public.modulus));
+    }
+    let key_pair = rcgen::KeyPair::try_from(key.contents.as_slice()).ok()?;
+    Some(key_pair.der_bytes() == cert.public_key().subject_public_key.data.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn write_cert(
+        dir: &Path,
+        names: &[&str],
+        days_valid: i64,
+        key: &KeyPair,
+    ) -> std::path::PathBuf {
+        let mut params =
+            CertificateParams::new(names.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+                .unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, names[0]);
+        params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+        params.not_after = OffsetDateTime::now_utc() + Duration::days(days_valid);
+        let cert = params.self_signed(key).unwrap();
+        let path = dir.join(format!("{}.pem", names[0]));
+        fs::write(&path, cert.pem()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_check_cert_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = KeyPair::generate().unwrap();
+        let key_path = dir.path().join("key.pem");
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        let host = Host::Domain("example.org".to_string());
+
+        let valid = write_cert(dir.path(), &["example.org"], 365, &key);
+        check_cert_pair(&valid, &key_path, &host, 30).unwrap();
+        let expiring = write_cert(dir.path(), &["*.example.org"], 10, &key);
+        check_cert_pair(&expiring, &key_path, &host, 30).unwrap();
+        let expired = write_cert(dir.path(), &["expired.org"], -1, &key);
+        assert!(check_cert_pair(&expired, &key_path, &host, 30).is_err());
+
+        let other_key = KeyPair::generate().unwrap();
+        let other_key_path = dir.path().join("other_key.pem");
+        fs::write(&other_key_path, other_key.serialize_pem()).unwrap();
+        assert!(check_cert_pair(&valid, &other_key_path, &host, 30).is_err());
+        assert!(check_private_key(&other_key_path).is_ok());
+        assert!(check_private_key(&valid).is_err());
+    }
+
+    #[test]
+    fn test_ensure_self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n",
+        )
+        .unwrap();
+        let conf = Config::load(&dir.path().to_path_buf()).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let ensure = |sans: &[&str]| {
+            ensure_self_signed(
+                &conf,
+                &cert_path,
+                &key_path,
+                sans.iter().map(|s| s.to_string()).collect(),
+            )
+            .unwrap();
+            fs::read_to_string(&cert_path).unwrap()
+        };
+
+        let first = ensure(&["a.example.org"]);
+        assert_eq!(
+            fs::metadata(&key_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let tracked = conf.local_conf.borrow().self_signed_certs["cert.pem"].clone();
+        assert_eq!(tracked.sans, ["a.example.org"]);
+        assert!(tracked.not_after > OffsetDateTime::now_utc() + Duration::days(364));
+        assert_eq!(ensure(&["a.example.org"]), first);
+        let renewed = ensure(&["b.example.org"]);
+        assert_ne!(renewed, first);
+        check_cert_pair(
+            &cert_path,
+            &key_path,
+            &Host::Domain("b.example.org".into()),
+            30,
+        )
+        .unwrap();
+
+        // Renewed when about to expire
+        conf.local_conf
+            .borrow_mut()
+            .self_signed_certs
+            .get_mut("cert.pem")
+            .unwrap()
+            .not_after = OffsetDateTime::now_utc() + Duration::days(1);
+        assert_ne!(ensure(&["b.example.org"]), renewed);
+
+        // Certs generated before tracking are adopted
+        conf.local_conf.borrow_mut().self_signed_certs.clear();
+        let legacy = rcgen::generate_simple_self_signed(["b.example.org".to_string()]).unwrap();
+        fs::write(&cert_path, legacy.cert.pem()).unwrap();
+        fs::write(&key_path, legacy.signing_key.serialize_pem()).unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(ensure(&["b.example.org"]), legacy.cert.pem());
+        assert_eq!(
+            fs::metadata(&key_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_ne!(ensure(&["c.example.org"]), legacy.cert.pem());
+
+        // Certs provided by the user are never replaced
+        conf.local_conf.borrow_mut().self_signed_certs.clear();
+        let key = KeyPair::generate().unwrap();
+        let user_cert = write_cert(dir.path(), &["user.example.org"], 365, &key);
+        fs::rename(user_cert, &cert_path).unwrap();
+        let user_cert = fs::read_to_string(&cert_path).unwrap();
+        assert_eq!(ensure(&["c.example.org"]), user_cert);
+    }
+
+    #[test]
+    fn test_cert_expiry_warning_days() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\ncert_expiry_warning_days = 5\n",
+        )
+        .unwrap();
+        let conf = Config::load(&dir.path().to_path_buf()).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let ensure = || {
+            ensure_self_signed(&conf, &cert_path, &key_path, vec!["a.example.org".into()]).unwrap();
+            fs::read_to_string(&cert_path).unwrap()
+        };
+        let first = ensure();
+        let set_not_after = |days| {
+            conf.local_conf
+                .borrow_mut()
+                .self_signed_certs
+                .get_mut("cert.pem")
+                .unwrap()
+                .not_after = OffsetDateTime::now_utc() + Duration::days(days);
+        };
+        set_not_after(10);
+        assert_eq!(ensure(), first);
+        set_not_after(4);
+        assert_ne!(ensure(), first);
+    }
+
+    #[test]
+    fn test_renewal_requires_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        fs::write(
+            path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        let generate = || {
+            let mut services = crate::load_services(&path).unwrap();
+            services.write_generated_files().unwrap();
+            services.config().restart_required.get()
+        };
+        assert!(generate());
+        assert!(!generate());
+
+        let local_conf_path = path.join("config.local.toml");
+        let mut local_conf: toml::Table =
+            toml::from_str(&fs::read_to_string(&local_conf_path).unwrap()).unwrap();
+        let expiring = OffsetDateTime::now_utc() + Duration::days(1);
+        local_conf["self_signed_certs"]["traefik-tls/fullchain.pem"]["not_after"] = expiring
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap()
+            .into();
+        fs::write(&local_conf_path, toml::to_string(&local_conf).unwrap()).unwrap();
+        assert!(generate());
+    }
+
+    #[test]
+    fn test_covers() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = |names: &[&str]| {
+            let pem = read_pem(&write_cert(dir.path(), names, 365, &key)).unwrap();
+            move |host: Host| covers(&pem.parse_x509().unwrap(), &host)
+        };
+        let wildcard = cert(&["*.example.org"]);
+        assert!(wildcard(Host::Domain("a.example.org".into())));
+        assert!(!wildcard(Host::Domain("a.b.example.org".into())));
+        assert!(!wildcard(Host::Domain("example.org".into())));
+        let ip = cert(&["10.0.0.1"]);
+        assert!(ip(Host::Ipv4([10, 0, 0, 1].into())));
+        assert!(!ip(Host::Ipv4([10, 0, 0, 2].into())));
+    }
+}
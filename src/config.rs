@@ -1,12 +1,23 @@
-use std::{cell::RefCell, collections::BTreeMap, fs, ops::Deref, path::PathBuf};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    ops::Deref,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
+use anyhow::Context;
 use rand::{RngExt, SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
 use url::{Host, Url};
 
 use crate::{
-    modules::{BbmriConfig, CcpConfig, DnpmConfig, EucaimConfig},
-    services::{BasicAuthUser, Service, TraefikConfig},
+    certs::SelfSignedCert,
+    enroll::BeamEnrollment,
+    modules::{BbmriConfig, BrokersConfig, CcpConfig, DnpmConfig, EucaimConfig},
+    services::{BasicAuthUser, Service, ServiceOverride, TraefikConfig},
 };
 
 #[derive(Debug, Deserialize)]
@@ -23,24 +34,52 @@ pub struct Config {
     /// Defaults to docker named volumes
     pub volume_dir: Option<PathBuf>,
     pub git_sync: Option<bool>,
+    /// Push local commits conflicting with the remote to `site/<site_id>/conflict-<date>`
+    #[serde(default)]
+    pub push_conflict_branch: bool,
     pub https_proxy_url: Option<Url>,
     pub ccp: Option<CcpConfig>,
     pub bbmri: Option<BbmriConfig>,
     pub dnpm: Option<DnpmConfig>,
     pub eucaim: Option<EucaimConfig>,
+    /// Additional beam brokers keyed by network name
+    #[serde(default)]
+    pub brokers: BrokersConfig,
     pub traefik: Option<TraefikConfig>,
+    /// Pass generated secrets as docker secret files to services supporting `*_FILE` variables instead of via `.env`
+    #[serde(default)]
+    pub secret_files: bool,
+    /// Certificates expiring within this many days produce a warning on every update and self-signed ones get renewed
+    #[serde(default = "default_cert_expiry_warning_days")]
+    pub cert_expiry_warning_days: u32,
+    /// Changes to generated compose services keyed by service name
+    #[serde(default)]
+    pub overrides: BTreeMap<String, ServiceOverride>,
     /// Path to the folder in which this config.toml was located
     #[serde(skip)]
     pub path: PathBuf,
 
     #[serde(skip)]
     pub local_conf: RefCell<LocalConf>,
+    /// Set when generating into a scratch copy of the config directory in which case nothing outside of `path` gets created
+    #[serde(skip)]
+    pub scratch: bool,
+    /// Set during generation if a file that is not tracked by git changed in a way the running services need to be restarted for
+    #[serde(skip)]
+    pub restart_required: Cell<bool>,
+    /// Key to encrypt config.local.toml with if set
+    #[serde(skip)]
+    local_conf_key: Option<LocalConfKey>,
 }
 
 fn default_image() -> String {
     "samply/rusthead:latest".to_string()
 }
 
+fn default_cert_expiry_warning_days() -> u32 {
+    30
+}
+
 #[derive(Debug, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Environment {
@@ -52,14 +91,19 @@ pub enum Environment {
 
 impl Config {
     pub fn load(path: &PathBuf) -> anyhow::Result<Self> {
+        Self::load_with_key(path, LocalConfKey::from_env()?)
+    }
+
+    fn load_with_key(path: &PathBuf, local_conf_key: Option<LocalConfKey>) -> anyhow::Result<Self> {
         anyhow::ensure!(
             path.is_absolute(),
             "Path to config must be absolute unlike {path:?}"
         );
         let mut conf: Config = toml::from_str(&std::fs::read_to_string(path.join("config.toml"))?)?;
         conf.path = path.clone();
-        let local_conf = fs::read_to_string(conf.local_conf_path())
-            .ok()
+        conf.local_conf_key = local_conf_key;
+        let local_conf = conf
+            .read_local_conf()?
             .and_then(|data| toml::from_str(&data).ok())
             .unwrap_or_else(|| {
                 eprintln!("Failed to read local config creating a new one");
@@ -69,48 +113,246 @@ impl Config {
         Ok(conf)
     }
 
-    pub fn trusted_ca_certs(&self) -> PathBuf {
+    /// Reads the encrypted local config if it exists falling back to the plaintext one.
+    /// Failing to decrypt is an error as creating a new local config would change every secret.
+    fn read_local_conf(&self) -> anyhow::Result<Option<String>> {
+        let encrypted_path = self.encrypted_local_conf_path();
+        let encrypted = match fs::read(&encrypted_path) {
+            Ok(encrypted) => encrypted,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(fs::read_to_string(self.local_conf_path()).ok());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {encrypted_path:?}")),
+        };
+        let Some(key) = &self.local_conf_key else {
+            anyhow::bail!(
+                "{encrypted_path:?} exists but neither BRIDGEHEAD_LOCAL_CONF_KEY nor BRIDGEHEAD_LOCAL_CONF_KEY_FILE is set"
+            );
+        };
+        let plaintext = age::decrypt(&key.0, &encrypted)
+            .with_context(|| format!("Failed to decrypt {encrypted_path:?}"))?;
+        Ok(Some(String::from_utf8(plaintext)?))
+    }
+
+    pub fn trusted_ca_certs(&self) -> anyhow::Result<PathBuf> {
         let dir = self.path.join("trusted-ca-certs");
-        fs::create_dir_all(&dir).unwrap();
-        dir
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        Ok(dir)
     }
 
     pub fn local_conf_path(&self) -> PathBuf {
         self.path.join("config.local.toml")
     }
 
+    pub fn encrypted_local_conf_path(&self) -> PathBuf {
+        self.path.join("config.local.toml.age")
+    }
+
     pub fn write_local_conf(&self) -> anyhow::Result<()> {
         let conf_str = toml::to_string_pretty(self.local_conf.borrow().deref())?;
-        fs::write(self.local_conf_path(), conf_str)?;
-        fs::write(
-            self.path.join(".env"),
-            self.local_conf.borrow().to_env()?.as_bytes(),
-        )?;
+        if let Some(key) = &self.local_conf_key {
+            let encrypted_path = self.encrypted_local_conf_path();
+            // Encryption is not deterministic so only rewrite the file if the content changed to keep the git history clean
+            let unchanged = fs::read(&encrypted_path)
+                .ok()
+                .and_then(|encrypted| age::decrypt(&key.0, &encrypted).ok())
+                .is_some_and(|old| old == conf_str.as_bytes());
+            if !unchanged {
+                let encrypted = age::encrypt_and_armor(&key.0.to_public(), conf_str.as_bytes())?;
+                fs::write(&encrypted_path, encrypted)?;
+            }
+            match fs::remove_file(self.local_conf_path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).context("Failed to remove the plaintext config.local.toml");
+                }
+                _ => {}
+            }
+        } else {
+            write_private(&self.local_conf_path(), &conf_str)?;
+        }
+        write_private(&self.path.join(".env"), &self.local_conf.borrow().to_env()?)?;
+        self.write_secret_files()
+    }
+
+    /// Writes the generated secrets used as docker secrets to `secrets/` readable only by the owner
+    fn write_secret_files(&self) -> anyhow::Result<()> {
+        let dir = self.path.join("secrets");
+        let local_conf = self.local_conf.borrow();
+        let secrets: BTreeMap<_, _> = local_conf
+            .generated_secrets
+            .iter()
+            .filter(|(_, s)| s.file)
+            .collect();
+        if secrets.is_empty() && !dir.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !secrets.contains_key(&entry.file_name().to_string_lossy().to_string()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        for (var, secret) in secrets {
+            write_private(&dir.join(var), &secret.value)?;
+        }
         Ok(())
     }
 }
 
+/// Writes a file containing secrets readable only by the owner
+/// Writes `content` to a file only the owner can read
+pub(crate) fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    // The mode is only applied on creation
+    make_private(path)
+}
+
+/// Restricts an existing file to the owner
+pub(crate) fn make_private(path: &Path) -> anyhow::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict permissions of {}", path.display()))
+}
+
+/// age identity used to encrypt config.local.toml
+pub struct LocalConfKey(age::x25519::Identity);
+
+impl std::fmt::Debug for LocalConfKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LocalConfKey(..)")
+    }
+}
+
+impl LocalConfKey {
+    /// Reads the key from `BRIDGEHEAD_LOCAL_CONF_KEY` or the file at `BRIDGEHEAD_LOCAL_CONF_KEY_FILE`
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        if let Ok(key) = std::env::var("BRIDGEHEAD_LOCAL_CONF_KEY") {
+            return Self::parse(&key).map(Some);
+        }
+        let Some(path) = std::env::var_os("BRIDGEHEAD_LOCAL_CONF_KEY_FILE") else {
+            return Ok(None);
+        };
+        Self::from_file(Path::new(&path)).map(Some)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("Failed to read key file {path:?}"))?;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            eprintln!("Warning: key file {path:?} is accessible by other users than its owner");
+        }
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses an age identity ignoring comments like the ones written by `age-keygen`
+    fn parse(key: &str) -> anyhow::Result<Self> {
+        let key = key
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .context("Key for config.local.toml is empty")?;
+        key.parse()
+            .map(Self)
+            .map_err(|e| anyhow::anyhow!("Invalid age key for config.local.toml: {e}"))
+    }
+
+    pub fn generate() -> (String, String) {
+        use age::secrecy::ExposeSecret;
+        let identity = age::x25519::Identity::generate();
+        (
+            identity.to_string().expose_secret().to_string(),
+            identity.to_public().to_string(),
+        )
+    }
+}
+
+/// An invalid combination of options in config.toml that serde can not catch on its own
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Dotted path to the offending key e.g. `ccp.obds2fhir`
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct LocalConf {
-    #[serde(default = "generate_seed")]
-    seed: u32,
+    /// Hex encoded 256-bit key all generated secrets are derived from
+    #[serde(
+        default,
+        with = "crate::utils::hex_key",
+        skip_serializing_if = "Option::is_none"
+    )]
+    master_key: Option<[u8; 32]>,
+    /// Legacy seed which keeps deriving the old secrets until `rusthead secrets migrate` is run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
     pub oidc: Option<BTreeMap<String, String>>,
     pub basic_auth_users: Option<BTreeMap<String, BasicAuthUser>>,
+    /// How often each generated secret has been rotated
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    secret_rotations: BTreeMap<String, u32>,
+    /// Self-signed certificates generated by rusthead keyed by their path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub self_signed_certs: BTreeMap<String, SelfSignedCert>,
+    /// CSRs generated for beam networks keyed by broker id. Missing on installations from before they were tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beam_enrollments: Option<BTreeMap<String, BeamEnrollment>>,
     #[serde(skip)]
-    pub generated_secrets: BTreeMap<String, String>,
+    pub generated_secrets: BTreeMap<String, GeneratedSecret>,
+}
+
+#[derive(Debug)]
+pub struct GeneratedSecret {
+    /// Name of the service that generated the secret
+    pub service: String,
+    pub name: String,
+    pub value: String,
+    /// Whether the secret is interpolated from `.env`
+    pub env: bool,
+    /// Whether the secret is mounted as a docker secret from `secrets/<VAR>`
+    pub file: bool,
 }
 
-fn generate_seed() -> u32 {
+fn generate_master_key() -> [u8; 32] {
     rand::rng().random()
 }
 
 impl Default for LocalConf {
     fn default() -> Self {
         LocalConf {
-            seed: generate_seed(),
+            master_key: Some(generate_master_key()),
+            seed: None,
             oidc: None,
             basic_auth_users: None,
+            secret_rotations: Default::default(),
+            self_signed_certs: Default::default(),
+            beam_enrollments: None,
             generated_secrets: Default::default(),
         }
     }
@@ -119,20 +361,71 @@ impl Default for LocalConf {
 impl LocalConf {
     #[must_use]
     pub fn generate_secret<const N: usize, T: Service>(&mut self, name: &str) -> String {
-        let name = format!(
-            "{}_{}",
-            <T as Service>::service_name().to_uppercase(),
-            name.to_uppercase()
-        )
-        .replace("-", "_");
-        let salt = name
-            .chars()
-            .fold(0_u64, |a, b| a.wrapping_mul(31).wrapping_add(b as u64));
-        let mut rng = StdRng::seed_from_u64(self.seed as u64 ^ salt);
-        let secret = crate::utils::secret_from_rng::<N>(&mut rng);
-        let var = format!("${{{name}}}");
-        self.generated_secrets.insert(name, secret);
-        var
+        format!("${{{}}}", self.generate_secret_var::<N, T>(name))
+    }
+
+    /// Like `generate_secret` but returns the variable name which is also the name of its docker secret
+    #[must_use]
+    pub fn generate_secret_var<const N: usize, T: Service>(&mut self, name: &str) -> String {
+        let service = <T as Service>::service_name();
+        let var_name =
+            format!("{}_{}", service.to_uppercase(), name.to_uppercase()).replace("-", "_");
+        let rotation = self.secret_rotations.get(&var_name).copied().unwrap_or(0);
+        let secret = match (self.master_key, self.seed) {
+            (None, Some(seed)) => {
+                let salt = var_name
+                    .chars()
+                    .fold(0_u64, |a, b| a.wrapping_mul(31).wrapping_add(b as u64));
+                let mut rng = StdRng::seed_from_u64(seed as u64 ^ salt ^ ((rotation as u64) << 32));
+                crate::utils::secret_from_rng::<N>(&mut rng)
+            }
+            _ => {
+                let key = self.master_key.get_or_insert_with(generate_master_key);
+                crate::utils::secret_from_key::<N>(key, &format!("{var_name}/{rotation}"))
+            }
+        };
+        self.generated_secrets.insert(
+            var_name.clone(),
+            GeneratedSecret {
+                service,
+                name: name.to_string(),
+                value: secret,
+                env: true,
+                file: false,
+            },
+        );
+        var_name
+    }
+
+    /// Replaces the legacy seed with a new master key which changes all generated secrets.
+    /// Returns false if the secrets are already derived from a master key.
+    pub fn migrate_to_master_key(&mut self) -> bool {
+        if self.master_key.is_some() {
+            return false;
+        }
+        self.master_key = Some(generate_master_key());
+        self.seed = None;
+        true
+    }
+
+    /// Marks all generated secrets matching the filters to be rotated on the next generation.
+    /// `secret` matches either the name passed to `generate_secret` or the variable name.
+    pub fn rotate_secrets(&mut self, service: Option<&str>, secret: Option<&str>) -> Vec<String> {
+        let rotated: Vec<_> = self
+            .generated_secrets
+            .iter()
+            .filter(|(var, s)| {
+                service.is_none_or(|service| s.service == service)
+                    && secret.is_none_or(|secret| {
+                        s.name.eq_ignore_ascii_case(secret) || var.eq_ignore_ascii_case(secret)
+                    })
+            })
+            .map(|(var, _)| var.clone())
+            .collect();
+        for var in &rotated {
+            *self.secret_rotations.entry(var.clone()).or_default() += 1;
+        }
+        rotated
     }
 
     pub fn to_env(&self) -> anyhow::Result<String> {
@@ -145,8 +438,8 @@ impl LocalConf {
                 writeln!(&mut env, "OIDC_{}=\"{}\"", k.to_uppercase(), v)?;
             }
         }
-        for (k, v) in &self.generated_secrets {
-            writeln!(&mut env, "{}=\"{}\"", k, v)?;
+        for (k, v) in self.generated_secrets.iter().filter(|(_, v)| v.env) {
+            writeln!(&mut env, "{}=\"{}\"", k, v.value)?;
         }
         Ok(env)
     }
@@ -168,25 +461,55 @@ mod tests {
         let mut s = insta::Settings::clone_current();
         s.set_prepend_module_to_snapshot(false);
         let _guard = s.bind_to_scope();
+        // Generated files are validated natively so docker compose is only an additional check where available
+        let has_docker_compose = Command::new("docker")
+            .args(["compose", "version"])
+            .output()
+            .is_ok_and(|out| out.status.success());
         insta::glob!("../tests/configs", "*.toml", |conf_path| {
             let temp_dir = tempfile::tempdir().unwrap();
             fs::copy(conf_path, temp_dir.path().join("config.toml")).unwrap();
+            // Files referenced by the config live in a directory named like it
+            if let Ok(entries) = fs::read_dir(conf_path.with_extension("")) {
+                for entry in entries {
+                    let entry = entry.unwrap();
+                    fs::copy(entry.path(), temp_dir.path().join(entry.file_name())).unwrap();
+                }
+            }
             let conf = Config::load(&temp_dir.path().to_path_buf()).unwrap();
-            conf.local_conf.borrow_mut().seed = 42;
+            {
+                let mut local_conf = conf.local_conf.borrow_mut();
+                local_conf.master_key = None;
+                local_conf.seed = Some(42);
+            }
             let conf: &'static _ = Box::leak(Box::new(conf));
             let mut services = ServiceMap::new(conf);
             modules::MODULES
                 .iter()
-                .for_each(|&m| services.install_module(m));
+                .try_for_each(|&m| services.install_module(m))
+                .unwrap();
             services.write_all().unwrap();
-            let has_beam_networks = !BEAM_NETWORKS.take().is_empty();
+            let env_mode = fs::metadata(temp_dir.path().join(".env"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(env_mode & 0o777, 0o600);
+            let beam_networks = BEAM_NETWORKS.take();
             let has_services = services.len() > 0;
             let tmp_dir_path = temp_dir.path().display().to_string();
-            let filters = [(tmp_dir_path.as_str(), "[TMP_DIR]")];
+            let filters = [
+                (tmp_dir_path.as_str(), "[TMP_DIR]"),
+                (r"\d{4}-\d{2}-\d{2}T[\d:.]+Z", "[DATETIME]"),
+            ];
             insta::glob!(temp_dir.path(), "**/*", |path| {
                 if path.is_dir() || path.extension() == Some("pem".as_ref()) {
                     return;
                 }
+                if let Ok(secret) = path.strip_prefix(temp_dir.path().join("secrets")) {
+                    let mode = fs::metadata(path).unwrap().permissions().mode();
+                    assert_eq!(mode & 0o777, 0o600, "{}", secret.display());
+                    return;
+                }
                 let file = std::fs::read_to_string(path).unwrap();
                 insta::allow_duplicates! {
                     insta::with_settings!({
@@ -204,39 +527,63 @@ mod tests {
                     });
                 };
             });
-            if !has_services {
+            if !has_services || !has_docker_compose {
                 return;
             }
-            if has_beam_networks {
-                // Fake enroll
-                let priv_key = rcgen::generate_simple_self_signed(vec![conf.site_id.clone()])
+            let bridgehead = |args: &[&str]| {
+                Command::new("./bridgehead")
+                    .current_dir(temp_dir.path())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .arg("compose")
+                    .args(args)
+                    .spawn()
                     .unwrap()
-                    .signing_key
-                    .serialize_pem();
+                    .wait_with_output()
+                    .unwrap()
+            };
+            if !beam_networks.is_empty() {
+                let out = bridgehead(&["up"]);
+                assert!(
+                    out.status.success()
+                        && String::from_utf8_lossy(&out.stdout).contains("is not enrolled"),
+                    "Did not start the services of enrolled networks"
+                );
+                for proxy in beam_networks.values() {
+                    let out = bridgehead(&["up", "-d", proxy]);
+                    assert!(
+                        !out.status.success(),
+                        "Started {proxy} of an unenrolled beam network"
+                    );
+                }
+                // Fake enroll
+                let key = rcgen::KeyPair::generate().unwrap();
                 fs::write(
                     temp_dir
                         .path()
                         .join("pki")
                         .join(format!("{}.priv.pem", conf.site_id)),
-                    priv_key,
+                    key.serialize_pem(),
                 )
                 .unwrap();
+                crate::enroll::record_enrollments(
+                    conf,
+                    &key,
+                    &beam_networks.keys().cloned().collect(),
+                );
+                BEAM_NETWORKS.set(beam_networks);
+                let files = services.compose().unwrap();
+                crate::bridgehead::Bridgehead::for_services(conf, services.graph(), &files)
+                    .write()
+                    .unwrap();
+                BEAM_NETWORKS.take();
             }
             fs::write(
                 temp_dir.path().join("docker-image.lock.yml"),
                 "services: {}\n",
             )
             .unwrap();
-            let out = Command::new("./bridgehead")
-                .current_dir(temp_dir.path())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .arg("compose")
-                .arg("config")
-                .spawn()
-                .unwrap()
-                .wait_with_output()
-                .unwrap();
+            let out = bridgehead(&["config"]);
             assert!(
                 out.status.success(),
                 "Generated invalid compose files\n stderr: {}\n stdout: {}",
@@ -245,4 +592,40 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    fn test_encrypted_local_conf() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        fs::write(
+            path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n",
+        )
+        .unwrap();
+        fs::write(path.join("config.local.toml"), "seed = 42\n").unwrap();
+        let (identity, _) = LocalConfKey::generate();
+        let key = || Some(LocalConfKey::parse(&identity).unwrap());
+
+        let conf = Config::load_with_key(&path, key()).unwrap();
+        conf.write_local_conf().unwrap();
+        assert!(!path.join("config.local.toml").exists());
+        let encrypted = fs::read(conf.encrypted_local_conf_path()).unwrap();
+        assert!(!String::from_utf8_lossy(&encrypted).contains("seed"));
+
+        let conf = Config::load_with_key(&path, key()).unwrap();
+        assert_eq!(conf.local_conf.borrow().seed, Some(42));
+        conf.write_local_conf().unwrap();
+        assert_eq!(
+            fs::read(conf.encrypted_local_conf_path()).unwrap(),
+            encrypted,
+            "Unchanged local config should not be re-encrypted"
+        );
+
+        assert!(Config::load_with_key(&path, None).is_err());
+        let (other_identity, _) = LocalConfKey::generate();
+        assert!(
+            Config::load_with_key(&path, Some(LocalConfKey::parse(&other_identity).unwrap()))
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,297 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use similar::TextDiff;
+use tempfile::TempDir;
+
+use crate::{config::Config, modules, services::ServiceMap};
+
+/// Entries of the config directory that are never read during generation and do not need to be copied into the scratch directory
+const SKIPPED_ENTRIES: &[&str] = &[".git", "services", "secrets"];
+
+/// Generated files that get compared in addition to everything in `services/`
+const GENERATED_FILES: &[&str] = &["bridgehead", ".env", "config.local.toml"];
+
+/// Generated files containing secrets whose values are left out of the diff
+const PRIVATE_FILES: &[&str] = &[".env", "config.local.toml"];
+
+/// Generates the bridgehead into a scratch copy of the config directory and returns a unified diff against the current files.
+/// Neither git nor docker are touched.
+pub fn dry_run(conf_path: &Path) -> anyhow::Result<String> {
+    let scratch = generate_in_scratch(conf_path)?;
+    diff_dirs(conf_path, scratch.path())
+}
+
+/// Generates the bridgehead into a scratch copy of `conf_path`
+pub fn generate_in_scratch(conf_path: &Path) -> anyhow::Result<TempDir> {
+    let scratch = scratch_copy(conf_path, None)?;
+    generate_in(scratch.path())?;
+    Ok(scratch)
+}
+
+/// Generates all files in a directory created by [`scratch_copy`]
+pub fn generate_in(scratch_path: &Path) -> anyhow::Result<()> {
+    let mut conf = Config::load(&scratch_path.to_path_buf()).context("Failed to load config")?;
+    conf.scratch = true;
+    let conf: &'static Config = Box::leak(Box::new(conf));
+    let mut services = ServiceMap::new(conf);
+    modules::MODULES
+        .iter()
+        .try_for_each(|&m| services.install_module(m))?;
+    services.write_generated_files()
+}
+
+/// Copies the config directory without its git repository, generated files and volumes into a new scratch directory.
+/// Files referenced by the config like broker root certificates or mounted files stay available that way.
+/// `config_toml` replaces the content of the current config.toml if given.
+pub fn scratch_copy(conf_path: &Path, config_toml: Option<&str>) -> anyhow::Result<TempDir> {
+    let scratch = tempfile::tempdir().context("Failed to create scratch directory")?;
+    let config_toml = match config_toml {
+        Some(config_toml) => config_toml.to_string(),
+        None => fs::read_to_string(conf_path.join("config.toml"))
+            .with_context(|| format!("Failed to read config.toml in {}", conf_path.display()))?,
+    };
+    let mut skipped: Vec<_> = SKIPPED_ENTRIES.iter().map(|e| conf_path.join(e)).collect();
+    // An unparsable config fails later on with a proper error
+    if let Some(volume_dir) = toml::from_str::<toml::Table>(&config_toml)
+        .ok()
+        .and_then(|conf| conf.get("volume_dir")?.as_str().map(PathBuf::from))
+    {
+        skipped.push(conf_path.join(volume_dir));
+    }
+    for entry in fs::read_dir(conf_path)
+        .with_context(|| format!("Failed to read {}", conf_path.display()))?
+    {
+        let entry = entry?;
+        copy_except(
+            &entry.path(),
+            &scratch.path().join(entry.file_name()),
+            &skipped,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to copy {} into the scratch directory",
+                entry.path().display()
+            )
+        })?;
+    }
+    fs::write(scratch.path().join("config.toml"), config_toml)?;
+    Ok(scratch)
+}
+
+fn diff_dirs(current: &Path, generated: &Path) -> anyhow::Result<String> {
+    // Generated files reference the config directory so we map the scratch paths back to the real ones
+    let mut path_replacements = vec![(
+        generated.display().to_string(),
+        current.display().to_string(),
+    )];
+    if let (Ok(generated), Ok(current)) = (generated.canonicalize(), current.canonicalize()) {
+        path_replacements.insert(
+            0,
+            (
+                generated.display().to_string(),
+                current.display().to_string(),
+            ),
+        );
+    }
+    let mut files: BTreeSet<PathBuf> = GENERATED_FILES.iter().map(PathBuf::from).collect();
+    for dir in [current, generated] {
+        let Ok(entries) = fs::read_dir(dir.join("services")) else {
+            continue;
+        };
+        for entry in entries {
+            files.insert(Path::new("services").join(entry?.file_name()));
+        }
+    }
+    let mut out = String::new();
+    for file in files {
+        let before = fs::read_to_string(current.join(&file)).unwrap_or_default();
+        let after = path_replacements.iter().fold(
+            fs::read_to_string(generated.join(&file)).unwrap_or_default(),
+            |content, (from, to)| content.replace(from, to),
+        );
+        if before == after {
+            continue;
+        }
+        let (before, after) = if PRIVATE_FILES.iter().any(|f| Path::new(f) == file) {
+            (
+                redact(&before, &after, "old"),
+                redact(&after, &before, "new"),
+            )
+        } else {
+            (before, after)
+        };
+        let name = file.display();
+        out.push_str(
+            &TextDiff::from_lines(&before, &after)
+                .unified_diff()
+                .header(&format!("a/{name}"), &format!("b/{name}"))
+                .to_string(),
+        );
+    }
+    Ok(out)
+}
+
+/// Replaces all assigned values of `content` with a placeholder that is marked as `changed` where `other` has a different value.
+/// Unquoted values are redacted as well as e.g. the legacy seed all secrets are derived from is a number.
+/// Works for both `.env` and TOML files as lines are keyed by the preceding table header and their key.
+fn redact(content: &str, other: &str, changed: &str) -> String {
+    let other_values = assigned_values(other);
+    let mut section = "";
+    let mut out = String::new();
+    for line in content.lines() {
+        match parse_assignment(line) {
+            Some((key, spacing, value)) => {
+                let placeholder = match other_values.get(&(section, key.trim())) {
+                    Some(&other) if other != value => format!("<{changed} secret>"),
+                    _ => "<secret>".to_string(),
+                };
+                if value.starts_with(['"', '\'']) {
+                    out.push_str(&format!("{key}={spacing}\"{placeholder}\""));
+                } else {
+                    out.push_str(&format!("{key}={spacing}{placeholder}"));
+                }
+            }
+            None => {
+                if line.trim_start().starts_with('[') {
+                    section = line.trim();
+                }
+                out.push_str(line);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn assigned_values(content: &str) -> BTreeMap<(&str, &str), &str> {
+    let mut section = "";
+    let mut values = BTreeMap::new();
+    for line in content.lines() {
+        if let Some((key, _, value)) = parse_assignment(line) {
+            values.insert((section, key.trim()), value);
+        } else if line.trim_start().starts_with('[') {
+            section = line.trim();
+        }
+    }
+    values
+}
+
+/// Splits `key = value` into the key, the whitespace after the `=` and the value
+fn parse_assignment(line: &str) -> Option<(&str, &str, &str)> {
+    if line.trim_start().starts_with(['#', '[']) {
+        return None;
+    }
+    let (key, rest) = line.split_once('=')?;
+    let value = rest.trim_start();
+    Some((key, &rest[..rest.len() - value.len()], value.trim_end()))
+}
+
+pub fn copy_recursive(from: &Path, to: &Path) -> anyhow::Result<()> {
+    copy_except(from, to, &[])
+}
+
+fn copy_except(from: &Path, to: &Path, skipped: &[PathBuf]) -> anyhow::Result<()> {
+    if skipped.iter().any(|s| s == from) {
+        return Ok(());
+    }
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_except(&entry.path(), &to.join(entry.file_name()), skipped)?;
+        }
+    } else if from.exists() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_does_not_write() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n",
+        )
+        .unwrap();
+        let diff = dry_run(&conf_path).unwrap();
+        assert!(diff.contains("+++ b/bridgehead"), "{diff}");
+        assert!(diff.contains(&format!(
+            "+cd {}",
+            conf_path.canonicalize().unwrap().display()
+        )));
+        assert_eq!(fs::read_dir(&conf_path).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_dry_run_does_not_create_volumes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let volume_dir = tempfile::tempdir().unwrap();
+        let volumes = volume_dir.path().join("volumes");
+        fs::write(
+            temp_dir.path().join("config.toml"),
+            format!(
+                "site_id = \"dummy\"\nhostname = \"dummy.local\"\nvolume_dir = {:?}\n\n[ccp]\nexporter = {{}}\n",
+                volumes.display().to_string()
+            ),
+        )
+        .unwrap();
+        let diff = dry_run(temp_dir.path()).unwrap();
+        assert!(diff.contains(&volumes.display().to_string()), "{diff}");
+        assert!(!volumes.exists());
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let current = tempfile::tempdir().unwrap();
+        let generated = tempfile::tempdir().unwrap();
+        fs::write(
+            current.path().join(".env"),
+            "# generated\nKEPT=\"kept-value\"\nCHANGED=\"old-value\"\n",
+        )
+        .unwrap();
+        fs::write(
+            generated.path().join(".env"),
+            "# generated\nKEPT=\"kept-value\"\nCHANGED=\"new-value\"\nADDED=\"added-value\"\n",
+        )
+        .unwrap();
+        fs::write(
+            current.path().join("config.local.toml"),
+            "seed = 1234567\n[generated_secrets.FOO]\nvalue = \"foo-value\"\n",
+        )
+        .unwrap();
+        fs::write(
+            generated.path().join("config.local.toml"),
+            "seed = 1234567\n[generated_secrets.FOO]\nvalue = \"foo-value\"\nenv = true\n",
+        )
+        .unwrap();
+        let diff = diff_dirs(current.path(), generated.path()).unwrap();
+        for value in [
+            "kept-value",
+            "old-value",
+            "new-value",
+            "added-value",
+            "foo-value",
+            "1234567",
+        ] {
+            assert!(!diff.contains(value), "{diff}");
+        }
+        assert!(diff.contains("-CHANGED=\"<old secret>\""), "{diff}");
+        assert!(diff.contains("+CHANGED=\"<new secret>\""), "{diff}");
+        assert!(diff.contains("+ADDED=\"<secret>\""), "{diff}");
+        assert!(diff.contains(" KEPT=\"<secret>\""), "{diff}");
+        assert!(diff.contains(" value = \"<secret>\""), "{diff}");
+        assert!(diff.contains(" seed = <secret>"), "{diff}");
+        assert!(diff.contains("+env = <secret>"), "{diff}");
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use askama::Template;
+use rcgen::{
+    CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_RSA_SHA256, PublicKeyData,
+};
+use rsa::{RsaPrivateKey, pkcs1::DecodeRsaPrivateKey, pkcs8::EncodePrivateKey, rand_core::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use x509_parser::pem::Pem;
+
+use crate::{
+    bridgehead::Bridgehead,
+    config::{Config, write_private},
+    load_services,
+    services::{
+        BEAM_NETWORKS, ServiceMap, compose::ComposeFile, prepare_priv_key_path, priv_key_path,
+    },
+};
+
+/// Size of generated beam proxy keys like the ones of the beam-enroll container
+const KEY_BITS: usize = 4096;
+
+/// CSR generated for a beam network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BeamEnrollment {
+    pub proxy_id: String,
+    /// SHA-256 of the public key the CSR was generated for
+    pub key_fingerprint: String,
+    /// Missing for networks enrolled before rusthead tracked enrollments
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub csr_generated: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "enroll_email.txt")]
+pub struct Enrollment {
+    pub site_id: String,
+    pub proxy_id: String,
+    pub broker_id: String,
+    pub csr: String,
+}
+
+/// Creates a CSR for every beam network of the config that is not enrolled yet and records them in config.local.toml.
+/// The existing private key is reused unless `new_key` is set as it is shared by all networks the site is already enrolled in.
+/// A new key requires enrolling in every network again.
+pub fn enroll(conf_path: &PathBuf, new_key: bool) -> anyhow::Result<Vec<Enrollment>> {
+    let (services, files, networks) = load_networks(conf_path)?;
+    let conf = services.config();
+    let key_path = prepare_priv_key_path(conf)?;
+    let (key, unenrolled) = if key_path.exists() && !new_key {
+        eprintln!("Reusing the private key {}", key_path.display());
+        let key = read_key(&key_path)?;
+        adopt_legacy_enrollments(conf, &networks);
+        let unenrolled: BTreeSet<_> = enrollment_problems(conf, &networks).into_keys().collect();
+        (key, unenrolled)
+    } else {
+        eprintln!("Generating a new private key {}", key_path.display());
+        let key = generate_key(KEY_BITS)?;
+        write_key(&key_path, &key)?;
+        // Enrollments of the old key are useless now
+        conf.local_conf.borrow_mut().beam_enrollments = Some(BTreeMap::new());
+        (key, networks)
+    };
+    let enrollments = unenrolled
+        .iter()
+        .map(|broker_id| {
+            let proxy_id = format!("{}.{broker_id}", conf.site_id);
+            let mut params = CertificateParams::default();
+            params.distinguished_name = DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, proxy_id.as_str());
+            let csr = params.serialize_request(&key)?.pem()?;
+            Ok(Enrollment {
+                site_id: conf.site_id.clone(),
+                proxy_id,
+                broker_id: broker_id.clone(),
+                csr,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    record_enrollments(conf, &key, &unenrolled);
+    conf.write_local_conf()?;
+    // The bridgehead script refuses to start networks that were not enrolled when it was generated
+    Bridgehead::for_services(conf, services.graph(), &files).write()?;
+    Ok(enrollments)
+}
+
+/// Prints the enrollment of every beam network and fails if any of them is not enrolled
+pub fn status(conf_path: &PathBuf) -> anyhow::Result<bool> {
+    let (services, _, networks) = load_networks(conf_path)?;
+    let conf = services.config();
+    let problems = enrollment_problems(conf, &networks);
+    let local_conf = conf.local_conf.borrow();
+    for broker_id in &networks {
+        if let Some(problem) = problems.get(broker_id) {
+            println!("{broker_id}: not enrolled as {problem}");
+            continue;
+        }
+        // Networks without problems have a recorded enrollment unless the key predates tracking them
+        let enrollment = local_conf
+            .beam_enrollments
+            .as_ref()
+            .map(|enrollments| &enrollments[broker_id]);
+        match enrollment {
+            Some(BeamEnrollment {
+                proxy_id,
+                csr_generated: Some(date),
+                ..
+            }) => println!("{broker_id}: CSR for {proxy_id} generated on {date}"),
+            Some(BeamEnrollment { proxy_id, .. }) => {
+                println!("{broker_id}: enrolled as {proxy_id} before enrollments were tracked")
+            }
+            None => println!(
+                "{broker_id}: enrolled as {}.{broker_id} before enrollments were tracked",
+                conf.site_id
+            ),
+        }
+    }
+    Ok(problems.is_empty())
+}
+
+/// Creates the services of the config and returns their compose files and the broker ids of their beam networks
+fn load_networks(
+    conf_path: &PathBuf,
+) -> anyhow::Result<(ServiceMap, BTreeMap<String, ComposeFile>, BTreeSet<String>)> {
+    let mut services = load_services(conf_path)?;
+    BEAM_NETWORKS.take();
+    let files = services.compose()?;
+    let networks: BTreeSet<_> = BEAM_NETWORKS.with_borrow(|nets| nets.keys().cloned().collect());
+    anyhow::ensure!(
+        !networks.is_empty(),
+        "config.toml does not enable any service that uses beam"
+    );
+    Ok((services, files, networks))
+}
+
+/// Records that CSRs for `networks` have been generated with `key`
+pub fn record_enrollments(conf: &Config, key: &KeyPair, networks: &BTreeSet<String>) {
+    let key_fingerprint = fingerprint(key);
+    let now = OffsetDateTime::now_utc();
+    let mut local_conf = conf.local_conf.borrow_mut();
+    let enrollments = local_conf.beam_enrollments.get_or_insert_default();
+    for broker_id in networks {
+        enrollments.insert(
+            broker_id.clone(),
+            BeamEnrollment {
+                proxy_id: format!("{}.{broker_id}", conf.site_id),
+                key_fingerprint: key_fingerprint.clone(),
+                csr_generated: Some(now),
+            },
+        );
+    }
+}
+
+/// Starts tracking the enrollments of `networks` if they are not tracked yet.
+/// Keys created by beam-enroll before enrollments were tracked are recorded as enrolled in the current networks.
+pub fn adopt_legacy_enrollments(conf: &Config, networks: &BTreeSet<String>) {
+    let mut local_conf = conf.local_conf.borrow_mut();
+    if local_conf.beam_enrollments.is_some() || networks.is_empty() {
+        return;
+    }
+    let Ok(key_fingerprint) = key_fingerprint(&priv_key_path(conf)) else {
+        local_conf.beam_enrollments = Some(BTreeMap::new());
+        return;
+    };
+    local_conf.beam_enrollments = Some(
+        networks
+            .iter()
+            .map(|broker_id| {
+                let enrollment = BeamEnrollment {
+                    proxy_id: format!("{}.{broker_id}", conf.site_id),
+                    key_fingerprint: key_fingerprint.clone(),
+                    csr_generated: None,
+                };
+                (broker_id.clone(), enrollment)
+            })
+            .collect(),
+    );
+}
+
+/// Checks the recorded enrollments against the current private key and site id.
+/// Returns why a network is not enrolled keyed by its broker id.
+pub fn enrollment_problems(conf: &Config, networks: &BTreeSet<String>) -> BTreeMap<String, String> {
+    if networks.is_empty() {
+        return BTreeMap::new();
+    }
+    let key_path = priv_key_path(conf);
+    let key_fingerprint = if key_path.exists() {
+        key_fingerprint(&key_path)
+            .map(Some)
+            .map_err(|e| format!("{e:#}"))
+    } else {
+        Ok(None)
+    };
+    let local_conf = conf.local_conf.borrow();
+    networks
+        .iter()
+        .filter_map(|broker_id| {
+            let proxy_id = format!("{}.{broker_id}", conf.site_id);
+            let enrollment = local_conf
+                .beam_enrollments
+                .as_ref()
+                .map(|enrollments| enrollments.get(broker_id));
+            let problem = match (&key_fingerprint, enrollment) {
+                (Err(e), _) => e.clone(),
+                (Ok(None), _) => format!("the private key {} does not exist", key_path.display()),
+                // Keys created by beam-enroll before enrollments were tracked are assumed to be enrolled in the current networks
+                (Ok(Some(_)), None) => return None,
+                (Ok(Some(_)), Some(None)) => "no CSR has been generated for it".into(),
+                (Ok(Some(_)), Some(Some(e))) if e.proxy_id != proxy_id => {
+                    format!("its CSR was generated for {}", e.proxy_id)
+                }
+                (Ok(Some(fp)), Some(Some(e))) if &e.key_fingerprint != fp => {
+                    "its CSR was generated for a different private key".into()
+                }
+                (Ok(Some(_)), Some(Some(_))) => return None,
+            };
+            Some((broker_id.clone(), problem))
+        })
+        .collect()
+}
+
+/// SHA-256 of the public key of the private key at `path` as recorded for enrollments
+pub fn key_fingerprint(path: &Path) -> anyhow::Result<String> {
+    read_any_key(path).map(|key| fingerprint(&key))
+}
+
+fn fingerprint(key: &KeyPair) -> String {
+    hex::encode(Sha256::digest(key.subject_public_key_info()))
+}
+
+fn read_key(path: &Path) -> anyhow::Result<KeyPair> {
+    KeyPair::from_pkcs8_der_and_sign_algo(&read_pkcs8(path)?.into(), &PKCS_RSA_SHA256)
+        .with_context(|| format!("{} is not an RSA key which beam requires", path.display()))
+}
+
+/// Reads a private key of any type ring supports
+fn read_any_key(path: &Path) -> anyhow::Result<KeyPair> {
+    KeyPair::try_from(read_pkcs8(path)?)
+        .with_context(|| format!("Failed to parse the private key {}", path.display()))
+}
+
+fn read_pkcs8(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let pem = Pem::iter_from_buffer(&content)
+        .next()
+        .with_context(|| format!("{} is empty", path.display()))?
+        .with_context(|| format!("{} is not PEM encoded", path.display()))?;
+    match pem.label.as_str() {
+        // ring only accepts PKCS#8 so PKCS#1 keys written by openssl are converted
+        "RSA PRIVATE KEY" => Ok(RsaPrivateKey::from_pkcs1_der(&pem.contents)
+            .and_then(|key| Ok(key.to_pkcs8_der()?))
+            .with_context(|| format!("Failed to parse the RSA key {}", path.display()))?
+            .as_bytes()
+            .to_vec()),
+        "PRIVATE KEY" => Ok(pem.contents),
+        label => anyhow::bail!(
+            "{} contains a {label} instead of a private key",
+            path.display()
+        ),
+    }
+}
+
+fn write_key(path: &Path, key: &KeyPair) -> anyhow::Result<()> {
+    write_private(path, &key.serialize_pem())
+}
+
+/// Generates an RSA key as rcgen can only generate them with aws-lc-rs
+fn generate_key(bits: usize) -> anyhow::Result<KeyPair> {
+    let key = RsaPrivateKey::new(&mut OsRng, bits).context("Failed to generate an RSA key")?;
+    KeyPair::from_pkcs8_der_and_sign_algo(
+        &key.to_pkcs8_der()?.as_bytes().to_vec().into(),
+        &PKCS_RSA_SHA256,
+    )
+    .context("Generated an invalid RSA key")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use x509_parser::{certification_request::X509CertificationRequest, prelude::FromDer};
+
+    use super::*;
+
+    #[test]
+    fn test_enroll() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\n[bbmri]\n",
+        )
+        .unwrap();
+        // Keys without tracked enrollments are assumed to be enrolled by beam-enroll already
+        fs::write(conf_path.join("config.local.toml"), "[beam_enrollments]\n").unwrap();
+        // Generating a 4096 bit key takes too long in debug builds
+        let key = generate_key(2048).unwrap();
+        let key_path = conf_path.join("pki/dummy.priv.pem");
+        fs::create_dir_all(key_path.parent().unwrap()).unwrap();
+        write_key(&key_path, &key).unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_key(&key_path, &key).unwrap();
+        assert_eq!(
+            fs::metadata(&key_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let enrollments = enroll(&conf_path, false).unwrap();
+        let proxy_ids: Vec<_> = enrollments.iter().map(|e| e.proxy_id.as_str()).collect();
+        assert_eq!(
+            proxy_ids,
+            [
+                "dummy.broker.bbmri.samply.de",
+                "dummy.broker.ccp-it.dktk.dkfz.de"
+            ]
+        );
+        for enrollment in &enrollments {
+            let (_, pem) = x509_parser::pem::parse_x509_pem(enrollment.csr.as_bytes()).unwrap();
+            let (_, csr) = X509CertificationRequest::from_der(&pem.contents).unwrap();
+            let info = &csr.certification_request_info;
+            assert_eq!(
+                info.subject.to_string(),
+                format!("CN={}", enrollment.proxy_id)
+            );
+            assert_eq!(info.subject_pki.raw, key.subject_public_key_info());
+            let email = enrollment.render().unwrap();
+            assert!(email.contains(&enrollment.csr), "{email}");
+        }
+        let script = fs::read_to_string(conf_path.join("bridgehead")).unwrap();
+        assert!(!script.contains("is not enrolled"), "{script}");
+        assert!(status(&conf_path).unwrap());
+        // The key stays the same across enrollments
+        assert_eq!(
+            read_key(&key_path).unwrap().serialize_der(),
+            key.serialize_der()
+        );
+
+        // Only networks that are not enrolled yet get a new CSR
+        let csr_generated = |conf_path: &PathBuf| {
+            let conf = Config::load(conf_path).unwrap();
+            let local_conf = conf.local_conf.borrow();
+            local_conf.beam_enrollments.as_ref().unwrap()["broker.ccp-it.dktk.dkfz.de"]
+                .csr_generated
+        };
+        let ccp_enrolled = csr_generated(&conf_path);
+        assert!(enroll(&conf_path, false).unwrap().is_empty());
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\n[bbmri]\n[eucaim]\nendpoint_type = \"eucaim-api\"\nprovider = \"test\"\nprovider_icon = \"none\"\n",
+        )
+        .unwrap();
+        let enrollments = enroll(&conf_path, false).unwrap();
+        let proxy_ids: Vec<_> = enrollments.iter().map(|e| e.proxy_id.as_str()).collect();
+        assert_eq!(proxy_ids, ["dummy.broker.eucaim.cancerimage.eu"]);
+        assert_eq!(csr_generated(&conf_path), ccp_enrolled);
+        assert!(status(&conf_path).unwrap());
+    }
+
+    #[test]
+    fn test_read_pkcs1_key() {
+        use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let key_path = temp_dir.path().join("openssl.priv.pem");
+        let key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+        fs::write(&key_path, key.to_pkcs1_pem(LineEnding::LF).unwrap()).unwrap();
+        assert_eq!(
+            read_key(&key_path).unwrap().serialize_der(),
+            key.to_pkcs8_der().unwrap().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_enrollment_problems() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\n",
+        )
+        .unwrap();
+        let (services, _, networks) = load_networks(&conf_path).unwrap();
+        let conf = services.config();
+        let problem = |conf| enrollment_problems(conf, &networks).into_values().next();
+        assert!(problem(conf).unwrap().contains("does not exist"));
+        adopt_legacy_enrollments(conf, &networks);
+        assert!(
+            conf.local_conf
+                .borrow()
+                .beam_enrollments
+                .as_ref()
+                .is_some_and(BTreeMap::is_empty)
+        );
+
+        let key_path = prepare_priv_key_path(conf).unwrap();
+        let key = KeyPair::generate().unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        assert_eq!(problem(conf).unwrap(), "no CSR has been generated for it");
+        record_enrollments(conf, &key, &networks);
+        assert_eq!(problem(conf), None);
+        fs::write(&key_path, KeyPair::generate().unwrap().serialize_pem()).unwrap();
+        assert_eq!(
+            problem(conf).unwrap(),
+            "its CSR was generated for a different private key"
+        );
+
+        // Keys of installations from before enrollments were tracked are adopted
+        let (services, _, _) = load_networks(&conf_path).unwrap();
+        let conf = services.config();
+        assert_eq!(problem(conf), None);
+        assert!(conf.local_conf.borrow().beam_enrollments.is_none());
+        adopt_legacy_enrollments(conf, &networks);
+        let local_conf = conf.local_conf.borrow();
+        let enrollment =
+            &local_conf.beam_enrollments.as_ref().unwrap()["broker.ccp-it.dktk.dkfz.de"];
+        assert_eq!(enrollment.proxy_id, "dummy.broker.ccp-it.dktk.dkfz.de");
+        assert!(enrollment.csr_generated.is_none());
+    }
+}
@@ -1,68 +1,226 @@
 use std::{
+    cell::Cell,
     collections::HashMap,
     fs,
     hash::{DefaultHasher, Hasher},
-    process::Command,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
 };
 
 use anyhow::Context;
+use git2::{
+    Commit, Cred, Diff, ErrorCode, FetchOptions, FileMode, IndexAddOption, ObjectType, Oid,
+    ProxyOptions, PushOptions, RemoteCallbacks, Repository, Signature, StashFlags, Status,
+    StatusOptions, Tree, TreeWalkMode, TreeWalkResult,
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+};
+use serde::Serialize;
+use time::OffsetDateTime;
 
 use crate::config::Config;
 
-fn is_git_repo(conf: &Config) -> bool {
-    fs::metadata(conf.path.join(".git")).map_or(false, |meta| meta.is_dir())
+/// Runs the git CLI in `path` and returns its stdout to set up test repositories
+#[cfg(test)]
+pub fn run(path: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = std::process::Command::new("git")
+        .current_dir(path)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Opens the repository in `path` or returns `None` if it is not one
+pub fn open(path: &Path) -> anyhow::Result<Option<Repository>> {
+    match Repository::open(path) {
+        Ok(repo) => Ok(Some(repo)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to open git repository {path:?}")),
+    }
+}
+
+/// Identity configured by the install step falling back to the same defaults
+fn signature(repo: &Repository) -> anyhow::Result<Signature<'static>> {
+    repo.signature()
+        .or_else(|_| Signature::now("Bridgehead", "bridgehead@samply.de"))
+        .context("Failed to create git signature")
+}
+
+fn head_commit(repo: &Repository) -> anyhow::Result<Option<Commit<'_>>> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => Ok(None),
+        Err(e) => Err(e).context("Failed to resolve HEAD"),
+    }
+}
+
+/// Commits the index on top of HEAD
+pub fn commit_index(repo: &Repository, message: &str) -> anyhow::Result<Oid> {
+    let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+    let signature = signature(repo)?;
+    let parent = head_commit(repo)?;
+    let message = format!("{}\n", message.trim_end());
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )
+    .context("Failed to commit changes")
+}
+
+/// Paths of all files changed in `diff`
+pub fn diff_paths(diff: &Diff) -> Vec<String> {
+    diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Summaries of the commits created by `DiffTracker::commit`
+const UPDATE_SUMMARIES: &[&str] = &[
+    "Nothing changed",
+    "Only local files changed",
+    "Git files changed",
+    "Local files and git changed",
+];
+
+/// Whether `commit` was created by an update as opposed to e.g. config changes pulled from the remote or a rollback
+pub fn is_update_commit(commit: &Commit) -> bool {
+    commit
+        .summary()
+        .is_ok_and(|summary| summary.is_some_and(|s| UPDATE_SUMMARIES.contains(&s)))
+}
+
+fn proxy_options() -> ProxyOptions<'static> {
+    let mut proxy = ProxyOptions::new();
+    // Uses http.proxy set by the install step
+    proxy.auto();
+    proxy
+}
+
+fn remote_callbacks<'a>(repo: &Repository) -> anyhow::Result<RemoteCallbacks<'a>> {
+    let config = repo.config()?;
+    let attempted = Cell::new(false);
+    let mut callbacks = RemoteCallbacks::new();
+    // Credentials in the remote url are used without asking. libgit2 keeps asking for rejected
+    // credentials so the configured credential helper only gets one attempt.
+    callbacks.credentials(move |url, username, _| {
+        if attempted.replace(true) {
+            return Err(git2::Error::from_str("Remote rejected the credentials"));
+        }
+        Cred::credential_helper(&config, url, username)
+    });
+    Ok(callbacks)
 }
 
 type LocalDiffHashes = HashMap<String, u64>;
 
+/// Written to the repository root if local commits conflict with the remote
+pub const CONFLICT_REPORT: &str = "sync-conflict.json";
+
+#[derive(Debug, Serialize)]
+pub struct ConflictReport {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// Remote commit the local commits could not be rebased onto
+    pub upstream: String,
+    /// Local commits missing from the remote as `<hash> <subject>`
+    pub local_commits: Vec<String>,
+    pub conflicting_files: Vec<String>,
+    /// Branch the local commits were pushed to if `push_conflict_branch` is enabled
+    pub pushed_branch: Option<String>,
+}
+
+/// Local modifications stashed before the update
+pub struct Stash {
+    commit: Oid,
+    /// Stashed changes as `<change> <path>` lines
+    changes: String,
+}
+
+/// Remote branch the checked out branch is pulled from and pushed to
+struct Upstream {
+    remote: String,
+    local_ref: String,
+    /// Name of the branch on the remote
+    merge_ref: String,
+    tracking_ref: String,
+}
+
 pub struct DiffTracker<'a> {
     conf: &'a Config,
+    repo: Repository,
     before_hashes: LocalDiffHashes,
-    stashed_changes: Option<String>,
+    stash: Option<Stash>,
+    /// Local commits conflict with the remote so pushing would fail
+    sync_conflict: bool,
 }
 
 pub enum DiffTrackerResult<'a> {
     Success(DiffTracker<'a>),
-    NeedsConfigReload,
+    /// Pass the stash on to [`DiffTracker::resume`] after reloading the config
+    NeedsConfigReload(Option<Stash>),
     NotAGitRepo,
 }
 
 impl<'a> DiffTracker<'a> {
     pub fn start(conf: &'a Config) -> anyhow::Result<DiffTrackerResult<'a>> {
-        // Required for git to create the files in the shared repository with group write permissions
+        Self::resume(conf, None)
+    }
+
+    /// Starts tracking with changes already stashed by a previous [`DiffTracker::start`]
+    pub fn resume(conf: &'a Config, stash: Option<Stash>) -> anyhow::Result<DiffTrackerResult<'a>> {
+        // Required to create the files in the shared repository with group write permissions
         unsafe { libc::umask(0o0002) };
-        if !is_git_repo(conf) {
-            println!("Directory is not a git repository yet skipping diff tracking");
+        let Some(repo) = open(&conf.path)? else {
+            eprintln!("Directory is not a git repository yet skipping diff tracking");
             return Ok(DiffTrackerResult::NotAGitRepo);
-        }
-        let tmp_self = Self {
+        };
+        let mut tmp_self = Self {
             conf,
+            repo,
             before_hashes: LocalDiffHashes::default(),
-            stashed_changes: None,
+            stash: None,
+            sync_conflict: false,
         };
         let git_diff = tmp_self.get_modified()?;
-        let stashed_changes = if !git_diff.is_empty() {
-            if tmp_self.is_initial_commit()? {
-                println!("No initial commit yet not stashing changes");
-                None
-            } else {
-                tmp_self.stash_all()?;
-                Some(git_diff)
-            }
-        } else {
+        let stash = if stash.is_some() || git_diff.is_empty() {
+            stash
+        } else if tmp_self.is_initial_commit()? {
+            eprintln!("No initial commit yet not stashing changes");
             None
+        } else {
+            Some(tmp_self.stash_all()?)
         };
+        let mut sync_conflict = false;
         if conf.git_sync.unwrap_or_else(|| tmp_self.has_remote()) {
-            let repo_hash_before = tmp_self.head_hash()?.stdout;
-            println!("Pulling changes from remote");
-            tmp_self.pull()?;
-            let repo_hash_after = tmp_self.head_hash()?.stdout;
+            let repo_hash_before = tmp_self.head_hash();
+            eprintln!("Pulling changes from remote");
+            if let Some(report) = tmp_self.pull()? {
+                eprintln!(
+                    "Warning: Local commits conflict with the remote in {}. Continuing with the local state, see {CONFLICT_REPORT}",
+                    report.conflicting_files.join(", ")
+                );
+                sync_conflict = true;
+            }
+            let repo_hash_after = tmp_self.head_hash();
             if repo_hash_before != repo_hash_after {
-                return Ok(DiffTrackerResult::NeedsConfigReload);
+                return Ok(DiffTrackerResult::NeedsConfigReload(stash));
             }
         }
         Ok(DiffTrackerResult::Success(Self {
-            stashed_changes,
+            stash,
+            sync_conflict,
             before_hashes: tmp_self
                 .hash_untracked_files()
                 .context("Failed to start tracking local files")?,
@@ -70,173 +228,502 @@ impl<'a> DiffTracker<'a> {
         }))
     }
 
-    fn git_command(&self) -> Command {
-        let mut cmd = Command::new("git");
-        cmd.current_dir(&self.conf.path);
-        cmd
-    }
-
+    /// Modified and untracked files as `<change> <path>` lines
     fn get_modified(&self) -> anyhow::Result<String> {
-        let status = self
-            .git_command()
-            .arg("status")
-            .arg("--porcelain")
-            .output()?;
-        if !status.status.success() {
-            anyhow::bail!(
-                "Failed to get status: {}",
-                String::from_utf8_lossy(&status.stderr)
-            );
-        }
-        let files = String::from_utf8_lossy(&status.stdout);
-        Ok(files.into_owned())
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true).recurse_untracked_dirs(true);
+        let statuses = self
+            .repo
+            .statuses(Some(&mut opts))
+            .context("Failed to get status")?;
+        Ok(statuses
+            .iter()
+            .filter_map(|entry| {
+                Some(format!(
+                    "{} {}\n",
+                    change(entry.status())?,
+                    entry.path().ok()?
+                ))
+            })
+            .collect())
     }
 
     fn hash_untracked_files(&self) -> anyhow::Result<LocalDiffHashes> {
-        let status = self
-            .git_command()
-            .args(["ls-files", "--others", "--exclude-standard", "--ignored"])
-            .output()?;
-        if !status.status.success() {
-            anyhow::bail!(
-                "Failed to get untracked files: {}",
-                String::from_utf8_lossy(&status.stderr)
-            );
-        }
-        let output = String::from_utf8_lossy(&status.stdout);
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(false)
+            .include_ignored(true)
+            .recurse_ignored_dirs(true);
+        let statuses = self
+            .repo
+            .statuses(Some(&mut opts))
+            .context("Failed to get untracked files")?;
         let mut hash_map = LocalDiffHashes::default();
-        for file_path in output.lines() {
+        for file_path in statuses
+            .iter()
+            .filter(|entry| entry.status().is_ignored())
+            .filter_map(|entry| entry.path().ok().map(ToString::to_string))
+        {
             let mut hasher = DefaultHasher::new();
-            let path = self.conf.path.join(file_path);
+            let path = self.conf.path.join(&file_path);
             let file = fs::read(&path)
                 .with_context(|| format!("Failed to read file: `{}`", path.display()))?;
             hasher.write(&file);
-            hash_map.insert(file_path.to_string(), hasher.finish());
+            hash_map.insert(file_path, hasher.finish());
         }
         Ok(hash_map)
     }
 
     fn is_initial_commit(&self) -> anyhow::Result<bool> {
-        Ok(!self.head_hash()?.status.success())
-    }
-
-    fn head_hash(&self) -> anyhow::Result<std::process::Output> {
-        Ok(self
-            .git_command()
-            .arg("rev-parse")
-            .arg("--verify")
-            .arg("HEAD")
-            .output()?)
-    }
-
-    fn stash_all(&self) -> anyhow::Result<()> {
-        println!("Stashing untracked changes:\n{}", self.get_modified()?);
-        let status = self
-            .git_command()
-            .args(["stash", "push", "-m", "auto-stash", "--include-untracked"])
-            .output()?;
-        if !status.status.success() {
-            anyhow::bail!(
-                "Failed to stash changes: {}",
-                String::from_utf8_lossy(&status.stderr)
-            );
+        Ok(head_commit(&self.repo)?.is_none())
+    }
+
+    fn head_hash(&self) -> Option<Oid> {
+        self.repo.head().ok().and_then(|head| head.target())
+    }
+
+    fn stash_all(&mut self) -> anyhow::Result<Stash> {
+        let changes = self.get_modified()?;
+        eprintln!("Stashing untracked changes:\n{changes}");
+        let signature = signature(&self.repo)?;
+        let commit = self
+            .repo
+            .stash_save(
+                &signature,
+                "auto-stash",
+                Some(StashFlags::INCLUDE_UNTRACKED),
+            )
+            .context("Failed to stash changes")?;
+        Ok(Stash { commit, changes })
+    }
+
+    /// Re-applies the stash. Files the update touched are not restored but kept in the stash instead.
+    fn restore_stash(&mut self, stash: &Stash) -> anyhow::Result<()> {
+        let mut latest = None;
+        self.repo.stash_foreach(|_, _, oid| {
+            latest = Some(*oid);
+            false
+        })?;
+        anyhow::ensure!(
+            latest == Some(stash.commit),
+            "Latest stash is not the auto-stash {} of this update",
+            stash.commit
+        );
+        let (tracked, untracked) = self.stashed_files(stash.commit)?;
+        let updated = {
+            let base = self.repo.find_commit(stash.commit)?.parent(0)?.tree()?;
+            diff_paths(&self.repo.diff_tree_to_workdir(Some(&base), None)?)
+        };
+        let (overwritten_tracked, restored_tracked): (Vec<_>, Vec<_>) =
+            tracked.into_iter().partition(|file| updated.contains(file));
+        let (overwritten_untracked, restored_untracked): (Vec<_>, Vec<_>) = untracked
+            .into_iter()
+            .partition(|file| self.conf.path.join(file).exists());
+        if overwritten_tracked.is_empty() && overwritten_untracked.is_empty() {
+            self.repo
+                .stash_pop(0, None)
+                .context("Failed to restore stashed changes")?;
+            eprintln!("Restored stashed changes");
+            return Ok(());
+        }
+        let (kept, message) = {
+            let stash_commit = self.repo.find_commit(stash.commit)?;
+            for file in &restored_tracked {
+                self.restore_file(&stash_commit.tree()?, file)?;
+            }
+            for file in &restored_untracked {
+                self.restore_file(&stash_commit.parent(2)?.tree()?, file)?;
+            }
+            let kept = self
+                .stash_files(&stash_commit, &overwritten_tracked, &overwritten_untracked)
+                .context("Failed to update the stash")?;
+            let message = stash_commit
+                .message()
+                .unwrap_or_default()
+                .trim_end()
+                .to_string();
+            (kept, message)
+        };
+        self.repo.stash_drop(0)?;
+        self.repo.reference_ensure_log("refs/stash")?;
+        self.repo.reference("refs/stash", kept, true, &message)?;
+        if !restored_tracked.is_empty() || !restored_untracked.is_empty() {
+            eprintln!("Restored stashed changes that the update did not overwrite");
         }
+        eprintln!(
+            "Warning: The update overwrote these files which were changed by hand:\n{}\nThe changes are kept in the stash {kept} (see `git stash list`)",
+            overwritten_tracked
+                .iter()
+                .chain(&overwritten_untracked)
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
         Ok(())
     }
 
-    fn git_add_all(&self) -> anyhow::Result<()> {
-        let status = self.git_command().arg("add").arg(".").output()?;
-        if !status.status.success() {
-            anyhow::bail!(
-                "Failed to add changes: {}",
-                String::from_utf8_lossy(&status.stderr)
-            );
+    /// Tracked files changed in the stash and the untracked files it holds
+    fn stashed_files(&self, stash: Oid) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let stash_commit = self.repo.find_commit(stash)?;
+        let base = stash_commit.parent(0)?.tree()?;
+        let tracked = diff_paths(&self.repo.diff_tree_to_tree(
+            Some(&base),
+            Some(&stash_commit.tree()?),
+            None,
+        )?);
+        // The third parent holds the untracked files if there were any
+        let mut untracked = Vec::new();
+        if let Ok(untracked_commit) = stash_commit.parent(2) {
+            untracked_commit
+                .tree()?
+                .walk(TreeWalkMode::PreOrder, |dir, entry| {
+                    if entry.kind() == Some(ObjectType::Blob) {
+                        untracked.push(format!("{dir}{}", entry.name().unwrap_or_default()));
+                    }
+                    TreeWalkResult::Ok
+                })?;
+        }
+        Ok((tracked, untracked))
+    }
+
+    /// Writes `file` as it is in `tree` to the working directory or removes it if it is not in there
+    fn restore_file(&self, tree: &Tree, file: &str) -> anyhow::Result<()> {
+        let path = self.conf.path.join(file);
+        let entry = match tree.get_path(Path::new(file)) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                return fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let blob = entry.to_object(&self.repo)?.peel_to_blob()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, blob.content())
+            .with_context(|| format!("Failed to restore {}", path.display()))?;
+        if entry.filemode() == i32::from(FileMode::BlobExecutable) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
         }
         Ok(())
     }
 
-    /// Commit all changes to git. Return true if there were any changes to local or git tracked files.
-    pub fn commit(self) -> anyhow::Result<bool> {
+    /// Creates a stash commit that only holds the changes of the given files of `stash_commit`
+    fn stash_files(
+        &self,
+        stash_commit: &Commit,
+        tracked: &[String],
+        untracked: &[String],
+    ) -> anyhow::Result<Oid> {
+        let signature = signature(&self.repo)?;
+        let base_commit = stash_commit.parent(0)?;
+        let base = base_commit.tree()?;
+        let with_files = |tree: &Tree, from: &Tree, files: &[String]| -> anyhow::Result<Oid> {
+            let mut update = TreeUpdateBuilder::new();
+            for file in files {
+                match from.get_path(Path::new(file)) {
+                    Ok(entry) => {
+                        let mode = match entry.filemode() {
+                            0o100755 => FileMode::BlobExecutable,
+                            0o120000 => FileMode::Link,
+                            _ => FileMode::Blob,
+                        };
+                        update.upsert(file, entry.id(), mode)
+                    }
+                    Err(_) => update.remove(file),
+                };
+            }
+            Ok(update.create_updated(&self.repo, tree)?)
+        };
+        let commit = |tree: Oid, message: &str, parents: &[&Commit]| -> anyhow::Result<Commit> {
+            let tree = self.repo.find_tree(tree)?;
+            let id = self
+                .repo
+                .commit(None, &signature, &signature, message, &tree, parents)?;
+            Ok(self.repo.find_commit(id)?)
+        };
+        // Staged changes of the kept files are part of the working tree changes already
+        let index_commit = commit(
+            base.id(),
+            stash_commit.parent(1)?.message().unwrap_or_default(),
+            &[&base_commit],
+        )?;
+        let mut parents = vec![base_commit.clone(), index_commit];
+        if !untracked.is_empty() {
+            let untracked_commit = stash_commit.parent(2)?;
+            let empty = self.repo.find_tree(self.repo.treebuilder(None)?.write()?)?;
+            let tree = with_files(&empty, &untracked_commit.tree()?, untracked)?;
+            parents.push(commit(
+                tree,
+                untracked_commit.message().unwrap_or_default(),
+                &[],
+            )?);
+        }
+        let kept = commit(
+            with_files(&base, &stash_commit.tree()?, tracked)?,
+            stash_commit.message().unwrap_or_default(),
+            &parents.iter().collect::<Vec<_>>(),
+        )?;
+        Ok(kept.id())
+    }
+
+    fn git_add_all(&self) -> anyhow::Result<()> {
+        let mut index = self.repo.index()?;
+        index
+            .add_all(["*"], IndexAddOption::DEFAULT, None)
+            .and_then(|()| index.update_all(["*"], None))
+            .and_then(|()| index.write())
+            .context("Failed to add changes")
+    }
+
+    /// Commit all changes to git and restore stashed changes. Return true if there were any changes to local or git tracked files.
+    pub fn commit(mut self) -> anyhow::Result<bool> {
         let git_diff = self.get_modified()?;
         let after_hashes = self.hash_untracked_files()?;
         let local_diff = compute_local_file_diff(&self.before_hashes, &after_hashes);
+        let changed = !(git_diff.is_empty() && local_diff.is_empty());
         let local_diff_str = local_diff
             .iter()
             .map(|(file, changed)| format!("{changed} {file}"))
             .collect::<Vec<_>>()
             .join("\n");
-        let mut cmd = self.git_command();
-        cmd.arg("commit").arg("-m");
-        match (git_diff.is_empty(), local_diff.is_empty()) {
-            (true, true) => {
-                cmd.arg("Nothing changed");
-                cmd.arg("--allow-empty");
-            }
-            (true, false) => {
-                cmd.arg(format!(
-                    "Only local files changed\n\nlocal:\n{local_diff_str}"
-                ));
-                cmd.arg("--allow-empty");
-            }
+        let mut message = match (git_diff.is_empty(), local_diff.is_empty()) {
+            (true, true) => "Nothing changed".to_string(),
+            (true, false) => format!("Only local files changed\n\nlocal:\n{local_diff_str}"),
             (false, true) => {
                 self.git_add_all()?;
-                cmd.arg(format!("Git files changed\n\ngit:\n{git_diff}"));
+                format!("Git files changed\n\ngit:\n{git_diff}")
             }
             (false, false) => {
                 self.git_add_all()?;
-                cmd.arg(format!(
-                    "Local files and git changed\n\ngit:\n{git_diff}\nlocal:\n{local_diff_str}"
-                ));
+                format!("Local files and git changed\n\ngit:\n{git_diff}\nlocal:\n{local_diff_str}")
             }
-        }
-        if let Some(ref stashed_changes) = self.stashed_changes {
-            cmd.arg("-m")
-                .arg(format!("stashed changes:\n{stashed_changes}"));
-        }
-        let status = cmd.output()?;
-        if !status.status.success() {
-            anyhow::bail!(
-                "Failed to commit changes: {}",
-                String::from_utf8_lossy(&status.stdout)
+        };
+        if let Some(ref stash) = self.stash {
+            message = format!(
+                "{}\n\nstashed changes:\n{}",
+                message.trim_end(),
+                stash.changes
             );
         }
-        if self.conf.git_sync.unwrap_or_else(|| self.has_remote()) {
-            println!("Pushing changes to remote");
+        commit_index(&self.repo, &message)?;
+        if self.sync_conflict {
+            eprintln!("Not pushing changes as they conflict with the remote");
+        } else if self.conf.git_sync.unwrap_or_else(|| self.has_remote()) {
+            eprintln!("Pushing changes to remote");
             self.push()?;
         }
-        Ok(!(git_diff.is_empty() && local_diff.is_empty()))
+        if let Some(stash) = self.stash.take() {
+            // The update itself succeeded so this should not fail it
+            if let Err(e) = self.restore_stash(&stash) {
+                eprintln!("Warning: {e:#}");
+            }
+        }
+        Ok(changed)
     }
 
     fn has_remote(&self) -> bool {
-        self.git_command()
-            .arg("remote")
-            .output()
-            .is_ok_and(|output| output.status.success() && !output.stdout.is_empty())
-    }
-
-    fn pull(&self) -> anyhow::Result<()> {
-        let output = self.git_command().arg("pull").arg("--rebase").output()?;
-        if !output.status.success() {
-            anyhow::bail!(
-                "Failed to pull changes: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+        self.repo.remotes().is_ok_and(|remotes| !remotes.is_empty())
+    }
+
+    fn upstream(&self) -> anyhow::Result<Upstream> {
+        let head = self.repo.head().context("Failed to resolve HEAD")?;
+        anyhow::ensure!(head.is_branch(), "HEAD is not a branch");
+        let local_ref = head.name().context("Branch name is not utf-8")?.to_string();
+        let buf_to_string = |buf: git2::Buf| {
+            buf.as_str()
+                .map(ToString::to_string)
+                .context("Upstream name is not utf-8")
+        };
+        let remote = self
+            .repo
+            .branch_upstream_remote(&local_ref)
+            .with_context(|| format!("{local_ref} has no upstream branch"))?;
+        Ok(Upstream {
+            remote: buf_to_string(remote)?,
+            merge_ref: buf_to_string(self.repo.branch_upstream_merge(&local_ref)?)?,
+            tracking_ref: buf_to_string(self.repo.branch_upstream_name(&local_ref)?)?,
+            local_ref,
+        })
+    }
+
+    /// Pulls changes from the remote rebasing local commits onto them. If local commits conflict
+    /// with them the rebase is aborted and the returned report is written to [`CONFLICT_REPORT`].
+    fn pull(&self) -> anyhow::Result<Option<ConflictReport>> {
+        let upstream = self.upstream()?;
+        let mut fetch_options = FetchOptions::new();
+        fetch_options
+            .remote_callbacks(remote_callbacks(&self.repo)?)
+            .proxy_options(proxy_options());
+        self.repo
+            .find_remote(&upstream.remote)?
+            .fetch::<&str>(&[], Some(&mut fetch_options), None)
+            .context("Failed to pull changes")?;
+        let upstream_oid = self.repo.refname_to_id(&upstream.tracking_ref)?;
+        let head_oid = self
+            .head_hash()
+            .context("Nothing to rebase onto the remote")?;
+        let report_path = self.conf.path.join(CONFLICT_REPORT);
+        if head_oid == upstream_oid || self.repo.graph_descendant_of(head_oid, upstream_oid)? {
+            // Nothing new on the remote
+        } else if self.repo.graph_descendant_of(upstream_oid, head_oid)? {
+            self.repo.checkout_tree(
+                &self.repo.find_object(upstream_oid, None)?,
+                Some(CheckoutBuilder::new().safe()),
+            )?;
+            self.repo.reference(
+                &upstream.local_ref,
+                upstream_oid,
+                true,
+                "pull: fast-forward",
+            )?;
+        } else if let Some(conflicting_files) = self.rebase(upstream_oid)? {
+            let mut report = ConflictReport {
+                time: OffsetDateTime::now_utc(),
+                upstream: upstream_oid.to_string(),
+                local_commits: self.local_commits(upstream_oid)?,
+                conflicting_files,
+                pushed_branch: None,
+            };
+            if self.conf.push_conflict_branch {
+                match self.push_conflict_branch(&upstream, report.time) {
+                    Ok(branch) => {
+                        eprintln!("Pushed local changes to {branch}");
+                        report.pushed_branch = Some(branch);
+                    }
+                    Err(e) => eprintln!("Warning: Failed to push conflicting changes: {e:#}"),
+                }
+            }
+            fs::write(&report_path, serde_json::to_string_pretty(&report)?)
+                .with_context(|| format!("Failed to write {}", report_path.display()))?;
+            return Ok(Some(report));
+        }
+        match fs::remove_file(&report_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context("Failed to remove resolved conflict report")
+            }
+            _ => Ok(None),
         }
-        Ok(())
     }
 
-    fn push(&self) -> anyhow::Result<()> {
-        let output = self.git_command().arg("push").output()?;
-        if !output.status.success() {
-            anyhow::bail!(
-                "Failed to push changes: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+    /// Rebases the checked out branch onto `upstream`. If that conflicts the rebase is aborted and the conflicting files are returned.
+    fn rebase(&self, upstream: Oid) -> anyhow::Result<Option<Vec<String>>> {
+        let signature = signature(&self.repo)?;
+        let upstream = self.repo.find_annotated_commit(upstream)?;
+        let mut rebase = self
+            .repo
+            .rebase(None, Some(&upstream), None, None)
+            .context("Failed to start rebase")?;
+        while let Some(operation) = rebase.next() {
+            operation?;
+            let index = self.repo.index()?;
+            if index.has_conflicts() {
+                let mut conflicting_files = index
+                    .conflicts()?
+                    .filter_map(|conflict| {
+                        let conflict = conflict.ok()?;
+                        let entry = conflict.our.or(conflict.their).or(conflict.ancestor)?;
+                        Some(String::from_utf8_lossy(&entry.path).into_owned())
+                    })
+                    .collect::<Vec<_>>();
+                conflicting_files.dedup();
+                rebase
+                    .abort()
+                    .context("Failed to abort rebase after conflicting pull")?;
+                return Ok(Some(conflicting_files));
+            }
+            match rebase.commit(None, &signature, None) {
+                // Same as git the commit is skipped if the remote already has its changes
+                Err(e) if e.code() == ErrorCode::Applied => {}
+                res => {
+                    res?;
+                }
+            }
         }
+        rebase.finish(Some(&signature))?;
+        Ok(None)
+    }
+
+    /// Commits that are missing from `upstream` as `<hash> <subject>`
+    fn local_commits(&self, upstream: Oid) -> anyhow::Result<Vec<String>> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push_head()?;
+        walk.hide(upstream)?;
+        walk.map(|oid| {
+            let commit = self.repo.find_commit(oid?)?;
+            Ok(format!(
+                "{} {}",
+                commit.id(),
+                commit.summary().ok().flatten().unwrap_or_default()
+            ))
+        })
+        .collect()
+    }
+
+    fn push_conflict_branch(
+        &self,
+        upstream: &Upstream,
+        time: OffsetDateTime,
+    ) -> anyhow::Result<String> {
+        let conflict_branch = format!("site/{}/conflict-{}", self.conf.site_id, time.date());
+        // The branch belongs to this site so a conflict later that day just replaces it
+        self.push_refspec(
+            &upstream.remote,
+            &format!("+{}:refs/heads/{conflict_branch}", upstream.local_ref),
+        )?;
+        Ok(conflict_branch)
+    }
+
+    fn push(&self) -> anyhow::Result<()> {
+        let upstream = self.upstream()?;
+        self.push_refspec(
+            &upstream.remote,
+            &format!("{}:{}", upstream.local_ref, upstream.merge_ref),
+        )
+        .context("Failed to push changes")
+    }
+
+    fn push_refspec(&self, remote: &str, refspec: &str) -> anyhow::Result<()> {
+        let mut rejected = Vec::new();
+        let mut callbacks = remote_callbacks(&self.repo)?;
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                rejected.push(format!("{refname}: {status}"));
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options
+            .remote_callbacks(callbacks)
+            .proxy_options(proxy_options());
+        self.repo
+            .find_remote(remote)?
+            .push(&[refspec], Some(&mut push_options))?;
+        drop(push_options);
+        anyhow::ensure!(
+            rejected.is_empty(),
+            "Remote rejected {}",
+            rejected.join(", ")
+        );
         Ok(())
     }
 }
 
+/// Single letter change of a status entry like in `git status --short`
+fn change(status: Status) -> Option<char> {
+    if status.is_ignored() {
+        None
+    } else if status.intersects(Status::INDEX_NEW | Status::WT_NEW) {
+        Some('A')
+    } else if status.intersects(Status::INDEX_DELETED | Status::WT_DELETED) {
+        Some('D')
+    } else if status.intersects(Status::INDEX_RENAMED | Status::WT_RENAMED) {
+        Some('R')
+    } else {
+        Some('M')
+    }
+}
+
 fn compute_local_file_diff<'a>(
     before_hashes: &'a LocalDiffHashes,
     after_hashes: &'a LocalDiffHashes,
@@ -263,3 +750,204 @@ fn compute_local_file_diff<'a>(
 
     diff
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const MINIMAL: &str = "site_id = \"dummy\"\nhostname = \"dummy.local\"\n";
+
+    fn commit_config(path: &Path, config_toml: &str, message: &str) {
+        fs::write(path.join("config.toml"), config_toml).unwrap();
+        run(path, &["add", "config.toml"]).unwrap();
+        run(path, &["commit", "-q", "-m", message]).unwrap();
+    }
+
+    fn clone(remote: &Path, path: &Path) {
+        run(remote, &["clone", "-q", ".", path.to_str().unwrap()]).unwrap();
+        run(path, &["config", "user.email", "test@example.org"]).unwrap();
+        run(path, &["config", "user.name", "Test"]).unwrap();
+    }
+
+    fn update(path: &Path) {
+        let conf = Config::load(&path.to_path_buf()).unwrap();
+        let Ok(DiffTrackerResult::Success(tracker)) = DiffTracker::start(&conf) else {
+            panic!("Failed to start diff tracking");
+        };
+        crate::load_services(&path.to_path_buf())
+            .unwrap()
+            .write_generated_files()
+            .unwrap();
+        tracker.commit().unwrap();
+    }
+
+    #[test]
+    fn test_restore_stash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path();
+        run(path, &["init", "-q"]).unwrap();
+        run(path, &["config", "user.email", "test@example.org"]).unwrap();
+        run(path, &["config", "user.name", "Test"]).unwrap();
+        commit_config(path, MINIMAL, "Initial");
+        update(path);
+
+        fs::write(path.join("notes.txt"), "hand written").unwrap();
+        fs::write(path.join("config.toml"), format!("# edited\n{MINIMAL}")).unwrap();
+        update(path);
+        assert_eq!(
+            fs::read_to_string(path.join("notes.txt")).unwrap(),
+            "hand written"
+        );
+        assert!(
+            fs::read_to_string(path.join("config.toml"))
+                .unwrap()
+                .starts_with("# edited")
+        );
+        assert!(run(path, &["stash", "list"]).unwrap().is_empty());
+
+        run(path, &["checkout", "-q", "config.toml"]).unwrap();
+        commit_config(
+            path,
+            &format!("{MINIMAL}[ccp]\nexporter = {{}}\n"),
+            "Exporter",
+        );
+        fs::write(path.join("services/ccp-exporter.yml"), "hand written").unwrap();
+        update(path);
+        assert_ne!(
+            fs::read_to_string(path.join("services/ccp-exporter.yml")).unwrap(),
+            "hand written"
+        );
+        // notes.txt was stashed along with it but only the overwritten file is kept in the stash
+        assert_eq!(
+            fs::read_to_string(path.join("notes.txt")).unwrap(),
+            "hand written"
+        );
+        assert_eq!(run(path, &["stash", "list"]).unwrap().lines().count(), 1);
+        let stashed = run(path, &["show", "--name-only", "--format=", "stash@{0}^3"]).unwrap();
+        assert_eq!(stashed.trim(), "services/ccp-exporter.yml");
+        fs::remove_file(path.join("services/ccp-exporter.yml")).unwrap();
+        run(path, &["stash", "pop", "-q"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("services/ccp-exporter.yml")).unwrap(),
+            "hand written"
+        );
+    }
+
+    /// Bare remote with an initial config and a `central` clone to change it
+    fn setup_remote(temp_dir: &Path) -> (PathBuf, PathBuf) {
+        let remote = temp_dir.join("remote.git");
+        let central = temp_dir.join("central");
+        fs::create_dir(&remote).unwrap();
+        run(&remote, &["init", "-q", "--bare", "-b", "main"]).unwrap();
+        clone(&remote, &central);
+        run(&central, &["checkout", "-q", "-b", "main"]).unwrap();
+        commit_config(&central, MINIMAL, "Initial");
+        run(&central, &["push", "-q", "-u", "origin", "main"]).unwrap();
+        (remote, central)
+    }
+
+    #[test]
+    fn test_pull_and_push() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (remote, central) = setup_remote(temp_dir.path());
+        let site = temp_dir.path().join("site");
+        clone(&remote, &site);
+        fs::write(site.join("notes.md"), "local notes").unwrap();
+        run(&site, &["add", "notes.md"]).unwrap();
+        run(&site, &["commit", "-q", "-m", "Notes"]).unwrap();
+        commit_config(&central, &format!("{MINIMAL}[ccp]\n"), "Central");
+        run(&central, &["push", "-q"]).unwrap();
+
+        let conf = Config::load(&site).unwrap();
+        let Ok(DiffTrackerResult::NeedsConfigReload(None)) = DiffTracker::start(&conf) else {
+            panic!("Pulling changes should reload the config");
+        };
+        assert!(
+            fs::read_to_string(site.join("config.toml"))
+                .unwrap()
+                .contains("[ccp]")
+        );
+        let log = run(&site, &["log", "--format=%s"]).unwrap();
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            ["Notes", "Central", "Initial"]
+        );
+
+        let conf = Config::load(&site).unwrap();
+        let Ok(DiffTrackerResult::Success(tracker)) = DiffTracker::start(&conf) else {
+            panic!("Nothing left to pull");
+        };
+        crate::load_services(&site)
+            .unwrap()
+            .write_generated_files()
+            .unwrap();
+        assert!(tracker.commit().unwrap());
+        assert!(run(&site, &["status", "--porcelain"]).unwrap().is_empty());
+        let message = run(&site, &["log", "-1", "--format=%B"]).unwrap();
+        assert!(
+            message.contains("A services/ccp-beam-proxy.yml"),
+            "{message}"
+        );
+        assert_eq!(
+            run(&remote, &["rev-parse", "main"]).unwrap(),
+            run(&site, &["rev-parse", "HEAD"]).unwrap()
+        );
+
+        run(&central, &["pull", "-q"]).unwrap();
+        commit_config(
+            &central,
+            &format!("{MINIMAL}[ccp]\n[bbmri]\n"),
+            "Fast forward",
+        );
+        run(&central, &["push", "-q"]).unwrap();
+        let conf = Config::load(&site).unwrap();
+        let Ok(DiffTrackerResult::NeedsConfigReload(None)) = DiffTracker::start(&conf) else {
+            panic!("Pulling changes should reload the config");
+        };
+        assert_eq!(
+            run(&central, &["rev-parse", "HEAD"]).unwrap(),
+            run(&site, &["rev-parse", "HEAD"]).unwrap()
+        );
+        assert!(run(&site, &["status", "--porcelain"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pull_conflict() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (remote, central) = setup_remote(temp_dir.path());
+        let site = temp_dir.path().join("site");
+        clone(&remote, &site);
+
+        commit_config(&central, &format!("{MINIMAL}[ccp]\n"), "Central");
+        run(&central, &["push", "-q"]).unwrap();
+        commit_config(
+            &site,
+            &format!("{MINIMAL}push_conflict_branch = true\n[bbmri]\n"),
+            "Local",
+        );
+        let local_head = run(&site, &["rev-parse", "HEAD"]).unwrap();
+
+        let conf = Config::load(&site).unwrap();
+        let Ok(DiffTrackerResult::Success(tracker)) = DiffTracker::start(&conf) else {
+            panic!("Conflicting pull should continue with the local state");
+        };
+        assert!(tracker.sync_conflict);
+        assert_eq!(tracker.repo.state(), git2::RepositoryState::Clean);
+        assert_eq!(run(&site, &["rev-parse", "HEAD"]).unwrap(), local_head);
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(site.join(CONFLICT_REPORT)).unwrap()).unwrap();
+        assert_eq!(
+            report["conflicting_files"],
+            serde_json::json!(["config.toml"])
+        );
+        assert_eq!(report["local_commits"].as_array().unwrap().len(), 1);
+        let branch = report["pushed_branch"].as_str().unwrap();
+        assert!(branch.starts_with("site/dummy/conflict-"), "{branch}");
+        assert_eq!(
+            run(&remote, &["rev-parse", &format!("refs/heads/{branch}")]).unwrap(),
+            local_head
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::{path::PathBuf, process::ExitCode};
 
 use anyhow::Context;
+use askama::Template;
 use clap::Parser;
 use config::Config;
 use services::ServiceMap;
@@ -8,11 +9,18 @@ use services::ServiceMap;
 use crate::{bridgehead::Bridgehead, git::DiffTrackerResult};
 
 mod bridgehead;
+mod certs;
 mod config;
+mod dry_run;
+mod enroll;
 mod git;
 mod modules;
+mod report;
+mod rollback;
+mod secrets;
 mod services;
 mod utils;
+mod validate;
 
 #[derive(Debug, clap::Subcommand)]
 enum BootstrapHelper {
@@ -31,11 +39,118 @@ enum Args {
     Update {
         #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
         config: PathBuf,
+        /// Print a diff of the generated files instead of writing them. Does not touch git or pull any images.
+        #[clap(long)]
+        dry_run: bool,
+        /// Print the update report written to update-report.json
+        #[clap(long, conflicts_with = "dry_run")]
+        report: bool,
+    },
+    /// Restore the generated files of an earlier update keeping the current config.toml
+    Rollback {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+        /// Commit of the update to restore
+        commit: Option<String>,
+        /// Number of updates to go back. Only commits of updates count, not e.g. config changes pulled from the remote.
+        #[clap(long, conflicts_with = "commit")]
+        steps: Option<u32>,
+    },
+    /// Check config.toml for errors without generating any files
+    Validate {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+    },
+    /// Print the dependency graph of all services that would be generated
+    Graph {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+        #[clap(short, long, value_enum, default_value_t)]
+        format: GraphFormat,
+    },
+    /// Manage generated secrets
+    Secrets {
+        #[clap(subcommand)]
+        command: SecretsCommand,
+    },
+    /// Generate the CSRs to enroll the beam proxies of all beam networks the config uses
+    Enroll {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+        /// Replace the existing private key which invalidates the enrollment in every network
+        #[clap(long)]
+        new_key: bool,
+        /// Only show which networks are enrolled. Fails if any is not.
+        #[clap(long, conflicts_with = "new_key")]
+        status: bool,
+    },
+    /// Explain which config keys and services caused a service to be installed
+    Explain {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+        /// Name of the service as it appears in services/
+        service: String,
     },
 }
 
+#[derive(Debug, clap::Subcommand)]
+enum SecretsCommand {
+    /// Derive new values for the selected generated secrets and regenerate .env
+    #[clap(group(clap::ArgGroup::new("selection").required(true).multiple(true)))]
+    Rotate {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+        /// Only rotate secrets generated by this service
+        #[clap(long, group = "selection")]
+        service: Option<String>,
+        /// Only rotate secrets with this name (e.g. `password` or `CCP_EXPORTER_DB_PASSWORD`)
+        #[clap(long, group = "selection")]
+        secret: Option<String>,
+    },
+    /// Generate a key to encrypt config.local.toml with
+    Keygen,
+    /// Derive all secrets from a new 256-bit master key instead of the legacy 32-bit seed.
+    /// This changes every generated secret including database passwords.
+    Migrate {
+        #[clap(short, long, env = "BRIDGEHEAD_CONFIG_PATH")]
+        config: PathBuf,
+    },
+}
+
+fn print_rotation(rotation: secrets::Rotation) {
+    for secret in &rotation.secrets {
+        println!("Rotated {secret}");
+    }
+    for db in &rotation.databases {
+        eprintln!(
+            "Warning: {db} stores its password in its data volume. Change it inside the database as well or it will reject the new one."
+        );
+    }
+    let services = Vec::from_iter(rotation.services).join(" ");
+    println!("Restart the affected services to apply the new secrets:");
+    println!("  ./bridgehead compose up -d --force-recreate {services}");
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum GraphFormat {
+    #[default]
+    Dot,
+    Json,
+}
+
+/// Loads the config and installs all modules without creating any services
+fn load_services(conf_path: &PathBuf) -> anyhow::Result<ServiceMap> {
+    let conf = Config::load(conf_path)
+        .with_context(|| format!("Failed to load config from {conf_path:?}"))?;
+    let mut services = ServiceMap::new(Box::leak(Box::new(conf)));
+    modules::MODULES
+        .iter()
+        .try_for_each(|&m| services.install_module(m))?;
+    Ok(services)
+}
+
 fn main() -> anyhow::Result<ExitCode> {
-    let conf_path = match Args::parse() {
+    let (conf_path, print_report) = match Args::parse() {
         Args::Bootstrap { helper: None } => {
             println!("{}", include_str!("../static/bootstrap.sh"));
             return Ok(ExitCode::SUCCESS);
@@ -46,26 +161,142 @@ fn main() -> anyhow::Result<ExitCode> {
             let conf = Config::load(&config)
                 .with_context(|| format!("Failed to load config from {config:?}"))?;
             let conf: &'static Config = Box::leak(Box::new(conf));
-            Bridgehead::new(&conf).write()?;
+            Bridgehead::new(conf).write()?;
             println!(
                 "Bridgehead bootstrap complete. Run `sudo ./bridgehead install` to install the bridgehead service."
             );
             return Ok(ExitCode::SUCCESS);
         }
-        Args::Update { config } => config,
+        Args::Validate { config } => {
+            let diagnostics = validate::validate(&config)?;
+            if diagnostics.is_empty() {
+                println!("config.toml is valid");
+                return Ok(ExitCode::SUCCESS);
+            }
+            for diagnostic in &diagnostics {
+                eprintln!("{diagnostic}");
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+        Args::Graph { config, format } => {
+            let services = load_services(&config)?;
+            match format {
+                GraphFormat::Dot => print!("{}", services.graph().to_dot()),
+                GraphFormat::Json => println!("{}", services.graph().to_json()?),
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Secrets {
+            command:
+                SecretsCommand::Rotate {
+                    config,
+                    service,
+                    secret,
+                },
+        } => {
+            print_rotation(secrets::rotate(
+                &config,
+                service.as_deref(),
+                secret.as_deref(),
+            )?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Secrets {
+            command: SecretsCommand::Keygen,
+        } => {
+            let (identity, recipient) = config::LocalConfKey::generate();
+            println!("# public key: {recipient}");
+            println!("{identity}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Secrets {
+            command: SecretsCommand::Migrate { config },
+        } => {
+            print_rotation(secrets::migrate(&config)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Enroll {
+            config,
+            status: true,
+            ..
+        } => {
+            return Ok(if enroll::status(&config)? {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            });
+        }
+        Args::Enroll {
+            config, new_key, ..
+        } => {
+            let enrollments = enroll::enroll(&config, new_key)?;
+            if enrollments.is_empty() {
+                println!(
+                    "All beam networks are enrolled already. Pass --new-key to replace the private key and enroll in every network again."
+                );
+                return Ok(ExitCode::SUCCESS);
+            }
+            for enrollment in &enrollments {
+                println!("{}\n", enrollment.render()?);
+            }
+            println!(
+                "Send the email{} above to the broker administrators. After the proxies got enrolled you may start the bridgehead.",
+                if enrollments.len() == 1 { "" } else { "s" }
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Rollback {
+            config,
+            commit,
+            steps,
+        } => {
+            let target = match commit {
+                Some(commit) => commit,
+                None => rollback::update_before(&config, steps.unwrap_or(1))?,
+            };
+            let rollback = rollback::rollback(&config, &target)?;
+            if !rollback.changed {
+                println!("Generated files already match {}", rollback.commit);
+                return Ok(ExitCode::SUCCESS);
+            }
+            println!("Rolled back to {}. Please restart", rollback.commit);
+            return Ok(ExitCode::from(3));
+        }
+        Args::Explain { config, service } => {
+            print!("{}", load_services(&config)?.graph().explain(&service)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Update {
+            config,
+            dry_run: true,
+            ..
+        } => {
+            let diff = dry_run::dry_run(&config)?;
+            if diff.is_empty() {
+                println!("No changes");
+            } else {
+                print!("{diff}");
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args::Update {
+            config,
+            dry_run: false,
+            report,
+        } => (config, report),
     };
     let conf = Config::load(&conf_path)
         .with_context(|| format!("Failed to load config from {conf_path:?}"))?;
     let conf: &'static mut Config = Box::leak(Box::new(conf));
-    let diff_tracker = match git::DiffTracker::start(&conf)? {
+    let diff_tracker = match git::DiffTracker::start(conf)? {
         DiffTrackerResult::Success(tracker) => Some(tracker),
         // git pull updated the repo -> reload the config
-        DiffTrackerResult::NeedsConfigReload => {
-            println!("Reloading config...");
+        DiffTrackerResult::NeedsConfigReload(stash) => {
+            eprintln!("Reloading config...");
             *conf = Config::load(&conf_path).with_context(|| {
                 format!("Failed to load config from {conf_path:?} after update")
             })?;
-            let DiffTrackerResult::Success(dt) = git::DiffTracker::start(&conf)? else {
+            let DiffTrackerResult::Success(dt) = git::DiffTracker::resume(conf, stash)? else {
                 anyhow::bail!("We just pulled so we should not need to reload the config again");
             };
             Some(dt)
@@ -75,18 +306,26 @@ fn main() -> anyhow::Result<ExitCode> {
     let mut services = ServiceMap::new(conf);
     modules::MODULES
         .iter()
-        .for_each(|&m| services.install_module(m));
+        .try_for_each(|&m| services.install_module(m))?;
+    let before = report::GeneratedState::capture(&conf.path)?;
     services.write_all()?;
-    if let Some(diff_tracker) = diff_tracker {
-        let needs_restart = diff_tracker.commit()?;
-        if needs_restart {
-            println!("Updated the bridgehead. Please restart");
-            Ok(ExitCode::from(3))
-        } else {
-            Ok(ExitCode::SUCCESS)
-        }
+    let after = report::GeneratedState::capture(&conf.path)?;
+    // Without a git repository this is most likely a new installation
+    let git_changed = match diff_tracker {
+        Some(diff_tracker) => diff_tracker.commit()?,
+        None => false,
+    };
+    // e.g. renewed self-signed certificates which are not tracked by git
+    let needs_restart = git_changed || conf.restart_required.get();
+    let report = report::UpdateReport::new(conf, &before, &after, needs_restart);
+    report.write(&conf.path)?;
+    if print_report {
+        println!("{}", report.to_json()?);
+    }
+    if needs_restart {
+        eprintln!("Updated the bridgehead. Please restart");
+        Ok(ExitCode::from(3))
     } else {
-        // Most likely a new installation
         Ok(ExitCode::SUCCESS)
     }
 }
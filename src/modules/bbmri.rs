@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use serde::Deserialize;
 
 use crate::config::Environment;
@@ -23,24 +25,39 @@ impl Module for Bbmri {
         &self,
         service_map: &mut crate::services::ServiceMap,
         conf: &'static crate::config::Config,
-    ) {
+    ) -> anyhow::Result<()> {
         let Some(bbmri_conf) = conf.bbmri.as_ref() else {
-            return;
+            return Ok(());
         };
-        service_map.install_default::<Blaze<Self>>();
+        service_map
+            .install_default::<Blaze<Self>>()
+            .config_key("bbmri");
         if bbmri_conf.eric {
             if let Environment::Acceptance = conf.environment {
-                service_map.install_with_config::<Focus<EricAcc, Blaze<Self>>>("develop".into());
+                service_map
+                    .install_with_config::<Focus<EricAcc, Blaze<Self>>>("develop".into())
+                    .config_key("bbmri.eric");
             } else {
-                service_map.install_with_config::<Focus<Eric, Blaze<Self>>>("main".into());
+                service_map
+                    .install_with_config::<Focus<Eric, Blaze<Self>>>("main".into())
+                    .config_key("bbmri.eric");
             }
         }
         if bbmri_conf.gbn {
-            service_map.install_with_config::<Focus<Gbn, Blaze<Self>>>("main".into());
+            service_map
+                .install_with_config::<Focus<Gbn, Blaze<Self>>>("main".into())
+                .config_key("bbmri.gbn");
         }
         if let Some(ds_conf) = &bbmri_conf.directory_sync {
-            service_map.install_with_config::<crate::services::DirectorySync<Self>>(ds_conf);
+            service_map
+                .install_with_config::<crate::services::DirectorySync<Self>>(ds_conf)
+                .config_key("bbmri.directory_sync");
         }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "bbmri"
     }
 }
 
@@ -49,9 +66,11 @@ impl BlazeProvider for Bbmri {
         "bbmri-blaze".to_owned()
     }
 
-    fn treafik_exposure() -> Option<crate::services::BlazeTraefikConfig> {
+    fn treafik_exposure(_conf: &crate::Config) -> Option<crate::services::BlazeTraefikConfig> {
         Some(crate::services::BlazeTraefikConfig {
-            middleware_and_user_name: "bbmri-blaze".to_owned(),
+            auth: crate::services::TraefikAuth::Basic {
+                middleware_and_user_name: "bbmri-blaze".to_owned(),
+            },
             path: "/bbmri-localdatamanagement".to_owned(),
         })
     }
@@ -68,8 +87,8 @@ impl BrokerProvider for Eric {
         "eric"
     }
 
-    fn root_cert() -> &'static str {
-        include_str!("../../static/beam/eric.root.crt.pem")
+    fn root_cert() -> Cow<'static, str> {
+        include_str!("../../static/beam/eric.root.crt.pem").into()
     }
 }
 
@@ -84,8 +103,8 @@ impl BrokerProvider for Gbn {
         "gbn"
     }
 
-    fn root_cert() -> &'static str {
-        include_str!("../../static/beam/gbn.root.crt.pem")
+    fn root_cert() -> Cow<'static, str> {
+        include_str!("../../static/beam/gbn.root.crt.pem").into()
     }
 }
 
@@ -100,7 +119,7 @@ impl BrokerProvider for EricAcc {
         "eric-acc"
     }
 
-    fn root_cert() -> &'static str {
-        include_str!("../../static/beam/bbmri.acc.root.crt.pem")
+    fn root_cert() -> Cow<'static, str> {
+        include_str!("../../static/beam/bbmri.acc.root.crt.pem").into()
     }
 }
@@ -0,0 +1,248 @@
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, fs, path::PathBuf, sync::LazyLock};
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    config::{Config, ConfigError},
+    modules::Module,
+    services::{
+        BeamConnect, BeamProxy, Blaze, BlazeProvider, BrokerProvider, Focus, ServiceMap,
+        beam_connect::LocalTarget,
+    },
+};
+
+/// `ServiceMap` keys services by their type and `BrokerProvider` is implemented on types so every
+/// configured broker needs its own `CustomBroker<N>`. Types can not be created at runtime so a fixed
+/// number of slots is compiled in which is enough for the few additional networks a site joins.
+const MAX_BROKERS: usize = 4;
+
+/// Beam brokers configured in config.toml keyed by their network name
+pub type BrokersConfig = BTreeMap<String, BrokerConfig>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub url: Url,
+    /// PEM encoded root certificate of the broker relative to config.toml
+    pub root_cert: PathBuf,
+    /// Run a focus with its own blaze connected to this broker
+    #[serde(default)]
+    pub focus: bool,
+    /// Local targets other sites of this network may reach through beam connect
+    #[serde(default)]
+    pub beam_connect: Vec<LocalTarget>,
+}
+
+#[derive(Debug, Clone)]
+struct LoadedBroker {
+    network_name: &'static str,
+    url: Url,
+    root_cert: String,
+}
+
+thread_local! {
+    /// Brokers of the config that was installed last by slot. Like `BEAM_NETWORKS` this is only valid for the current `ServiceMap`.
+    static BROKERS: RefCell<[Option<LoadedBroker>; MAX_BROKERS]> = const { RefCell::new([const { None }; MAX_BROKERS]) };
+}
+
+/// Broker provider backed by the `N`th broker of the `brokers` section
+pub struct CustomBroker<const N: usize>;
+
+impl<const N: usize> CustomBroker<N> {
+    fn loaded() -> Option<LoadedBroker> {
+        BROKERS.with_borrow(|brokers| brokers[N].clone())
+    }
+
+    /// Registers the broker for this slot before installing its services so they can never observe an empty slot
+    fn install(service_map: &mut ServiceMap, broker: LoadedBroker, conf: &'static BrokerConfig) {
+        let config_key = format!("brokers.{}", broker.network_name);
+        BROKERS.with_borrow_mut(|brokers| brokers[N] = Some(broker));
+        service_map
+            .install_default::<BeamProxy<Self>>()
+            .config_key(&config_key);
+        if conf.focus {
+            service_map
+                .install_with_config::<Focus<Self, Blaze<Self>>>("main".into())
+                .config_key(&config_key);
+        }
+        if !conf.beam_connect.is_empty() {
+            service_map
+                .install_default::<BeamConnect<Self>>()
+                .config_key(config_key)
+                .post_install(|bc| {
+                    for target in &conf.beam_connect {
+                        bc.add_local_target(target.clone());
+                    }
+                });
+        }
+    }
+}
+
+// The fallbacks are only observable by a service that was installed without `CustomBroker::install`
+// and `check_configured` makes creating such a service fail
+impl<const N: usize> BrokerProvider for CustomBroker<N> {
+    fn broker_url() -> Url {
+        Self::loaded().map_or_else(|| UNCONFIGURED_URL.clone(), |b| b.url)
+    }
+
+    fn network_name() -> &'static str {
+        Self::loaded().map_or("unconfigured", |b| b.network_name)
+    }
+
+    fn root_cert() -> Cow<'static, str> {
+        Self::loaded().map_or(Cow::Borrowed(""), |b| Cow::Owned(b.root_cert))
+    }
+
+    fn check_configured() -> anyhow::Result<()> {
+        anyhow::ensure!(
+            Self::loaded().is_some(),
+            "No broker is configured for custom broker slot {N}"
+        );
+        Ok(())
+    }
+}
+
+static UNCONFIGURED_URL: LazyLock<Url> =
+    LazyLock::new(|| Url::parse("https://unconfigured.invalid").unwrap());
+
+impl<const N: usize> BlazeProvider for CustomBroker<N> {
+    fn balze_service_name() -> String {
+        format!("{}-blaze", Self::network_name())
+    }
+}
+
+type Installer = fn(&mut ServiceMap, LoadedBroker, &'static BrokerConfig);
+
+const INSTALLERS: [Installer; MAX_BROKERS] = [
+    CustomBroker::<0>::install,
+    CustomBroker::<1>::install,
+    CustomBroker::<2>::install,
+    CustomBroker::<3>::install,
+];
+
+pub struct Brokers;
+
+impl Module for Brokers {
+    fn install(&self, service_map: &mut ServiceMap, conf: &'static Config) -> anyhow::Result<()> {
+        if conf.brokers.len() > MAX_BROKERS {
+            return Err(ConfigError::new(
+                "brokers",
+                format!("at most {MAX_BROKERS} brokers can be configured"),
+            )
+            .into());
+        }
+        BROKERS.set([const { None }; MAX_BROKERS]);
+        let mut loaded = Vec::with_capacity(conf.brokers.len());
+        for (name, broker_conf) in &conf.brokers {
+            let key = format!("brokers.{name}");
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                return Err(ConfigError::new(
+                    key,
+                    "network name may only contain lowercase letters, digits and dashes",
+                )
+                .into());
+            }
+            if broker_conf.url.host().is_none() {
+                return Err(
+                    ConfigError::new(format!("{key}.url"), "broker url has no host").into(),
+                );
+            }
+            let root_cert_path = conf.path.join(&broker_conf.root_cert);
+            let root_cert = fs::read_to_string(&root_cert_path).map_err(|e| {
+                ConfigError::new(
+                    format!("{key}.root_cert"),
+                    format!("failed to read {}: {e}", root_cert_path.display()),
+                )
+            })?;
+            crate::certs::check_ca_cert(&root_cert_path, conf.cert_expiry_warning_days)
+                .map_err(|e| ConfigError::new(format!("{key}.root_cert"), format!("{e:#}")))?;
+            loaded.push((
+                LoadedBroker {
+                    network_name: name,
+                    url: broker_conf.url.clone(),
+                    root_cert,
+                },
+                broker_conf,
+            ));
+        }
+        for ((broker, broker_conf), install) in loaded.into_iter().zip(INSTALLERS) {
+            install(service_map, broker, broker_conf);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "brokers"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(temp_dir: &tempfile::TempDir, brokers: &str) -> anyhow::Result<ServiceMap> {
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::copy(
+            "tests/configs/brokers/pilot.root.crt.pem",
+            conf_path.join("pilot.root.crt.pem"),
+        )
+        .unwrap();
+        fs::write(
+            conf_path.join("config.toml"),
+            format!("site_id = \"dummy\"\nhostname = \"dummy.local\"\n{brokers}"),
+        )
+        .unwrap();
+        crate::load_services(&conf_path)
+    }
+
+    fn config_key(res: anyhow::Result<ServiceMap>) -> String {
+        res.unwrap_err().downcast::<ConfigError>().unwrap().key
+    }
+
+    #[test]
+    fn test_custom_brokers() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut services = load(
+            &temp_dir,
+            "[brokers.pilot]\nurl = \"https://broker.pilot.example.org\"\nroot_cert = \"pilot.root.crt.pem\"\n",
+        )
+        .unwrap();
+        let files = services.compose().unwrap();
+        assert!(files.contains_key("pilot-beam-proxy"));
+        assert!(!files.contains_key("pilot-focus"));
+        assert_eq!(CustomBroker::<0>::broker_id(), "broker.pilot.example.org");
+        assert!(CustomBroker::<1>::check_configured().is_err());
+
+        assert_eq!(
+            config_key(load(
+                &temp_dir,
+                "[brokers.Pilot]\nurl = \"https://broker.pilot.example.org\"\nroot_cert = \"pilot.root.crt.pem\"\n"
+            )),
+            "brokers.Pilot"
+        );
+        assert_eq!(
+            config_key(load(
+                &temp_dir,
+                "[brokers.pilot]\nurl = \"https://broker.pilot.example.org\"\nroot_cert = \"missing.pem\"\n"
+            )),
+            "brokers.pilot.root_cert"
+        );
+    }
+
+    #[test]
+    fn test_network_name_conflict() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut services = load(
+            &temp_dir,
+            "[ccp]\n[brokers.ccp]\nurl = \"https://broker.pilot.example.org\"\nroot_cert = \"pilot.root.crt.pem\"\n",
+        )
+        .unwrap();
+        let err = services.compose().unwrap_err().to_string();
+        assert!(err.contains("\"ccp-beam-proxy\""), "{err}");
+    }
+}
@@ -1,14 +1,14 @@
-use std::str::FromStr;
+use std::{borrow::Cow, str::FromStr};
 
 use serde::Deserialize;
 use url::Url;
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigError},
     services::{
-        Blaze, BlazeProvider, BlazeTraefikConfig, BrokerProvider, DataShield, Exporter, Focus,
-        IdManagement, IdManagementConfig, OidcProvider, ServiceMap, Teiler, TeilerConfig,
-        Transfair, TransfairConfig,
+        AuthMethod, Blaze, BlazeProvider, BlazeTraefikConfig, BrokerProvider, DataShield, Exporter,
+        Focus, ForwardAuth, IdManagement, IdManagementConfig, OidcProvider, ServiceMap, Teiler,
+        TeilerConfig, TraefikAuth, Transfair, TransfairConfig,
         obds2fhir::{Obds2Fhir, Obds2FhirConfig},
         podest2fhir::{Podest2Fhir, Podest2FhirConfig},
     },
@@ -27,6 +27,9 @@ pub struct CcpConfig {
     datashield: Option<Empty>,
     obds2fhir: Option<Obds2FhirConfig>,
     podest2fhir: Option<Podest2FhirConfig>,
+    /// How the local blaze exposed under /ccp-localdatamanagement is protected
+    #[serde(default)]
+    blaze_auth: AuthMethod,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,37 +38,76 @@ struct Empty {}
 pub struct CcpDefault;
 
 impl Module for CcpDefault {
-    fn install(&self, service_map: &mut ServiceMap, conf: &'static crate::Config) {
+    fn install(
+        &self,
+        service_map: &mut ServiceMap,
+        conf: &'static crate::Config,
+    ) -> anyhow::Result<()> {
         let Some(ccp_conf) = conf.ccp.as_ref() else {
-            return;
+            return Ok(());
         };
-        service_map.install_with_config::<Focus<Self, Blaze<Self>>>("main".into());
+        service_map
+            .install_with_config::<Focus<Self, Blaze<Self>>>("main".into())
+            .config_key("ccp");
+        if ccp_conf.blaze_auth == AuthMethod::Oidc {
+            service_map
+                .install_default::<ForwardAuth<Self>>()
+                .config_key("ccp.blaze_auth");
+        } else if ccp_conf
+            .obds2fhir
+            .as_ref()
+            .is_some_and(|c| c.auth == AuthMethod::Oidc)
+        {
+            service_map
+                .install_default::<ForwardAuth<Self>>()
+                .config_key("ccp.obds2fhir.auth");
+        }
         if let Some(idm_conf) = &ccp_conf.id_manager {
-            service_map.install_with_config::<IdManagement<Self>>((idm_conf, conf));
+            service_map
+                .install_with_config::<IdManagement<Self>>((idm_conf, conf))
+                .config_key("ccp.id_manager");
             if let Some(obds_conf) = &ccp_conf.obds2fhir {
-                service_map.install_with_config::<Obds2Fhir<IdManagement<Self>>>((
-                    obds_conf.clone(),
-                    conf,
-                ));
+                service_map
+                    .install_with_config::<Obds2Fhir<IdManagement<Self>>>((obds_conf.clone(), conf))
+                    .config_key("ccp.obds2fhir");
             }
         } else if ccp_conf.obds2fhir.is_some() {
-            panic!("obds2fhir rest requires id mamanger setup")
+            return Err(ConfigError::new(
+                "ccp.obds2fhir",
+                "obds2fhir rest requires ccp.id_manager to be configured",
+            )
+            .into());
         }
         if let Some(transfair_conf) = &ccp_conf.transfair {
-            service_map.install_with_config::<Transfair<Self>>((transfair_conf, conf));
+            service_map
+                .install_with_config::<Transfair<Self>>((transfair_conf, conf))
+                .config_key("ccp.transfair");
         }
         if let Some(Empty {}) = &ccp_conf.datashield {
-            service_map.install_default::<DataShield<Self>>();
+            service_map
+                .install_default::<DataShield<Self>>()
+                .config_key("ccp.datashield");
         }
         if let Some(Empty {}) = &ccp_conf.exporter {
-            service_map.install_default::<Exporter<Self>>();
+            service_map
+                .install_default::<Exporter<Self>>()
+                .config_key("ccp.exporter");
         }
         if let Some(teiler_conf) = &ccp_conf.teiler {
-            service_map.install_with_config::<Teiler<Self>>((teiler_conf, conf));
+            service_map
+                .install_with_config::<Teiler<Self>>((teiler_conf, conf))
+                .config_key("ccp.teiler");
         }
         if let Some(podest_conf) = &ccp_conf.podest2fhir {
-            service_map.install_with_config::<Podest2Fhir<Self>>((podest_conf.clone(), "dktk"));
+            service_map
+                .install_with_config::<Podest2Fhir<Self>>((podest_conf.clone(), "dktk"))
+                .config_key("ccp.podest2fhir");
         }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "ccp"
     }
 }
 
@@ -74,9 +116,10 @@ impl BlazeProvider for CcpDefault {
         "ccp-blaze".into()
     }
 
-    fn treafik_exposure() -> Option<BlazeTraefikConfig> {
+    fn treafik_exposure(conf: &Config) -> Option<BlazeTraefikConfig> {
+        let method = conf.ccp.as_ref().map(|c| c.blaze_auth).unwrap_or_default();
         Some(BlazeTraefikConfig {
-            middleware_and_user_name: "ccp-blaze".into(),
+            auth: TraefikAuth::new::<Self>(method, "ccp-blaze".into()),
             path: "/ccp-localdatamanagement".into(),
         })
     }
@@ -91,8 +134,8 @@ impl BrokerProvider for CcpDefault {
         Url::from_str("https://broker.ccp-it.dktk.dkfz.de").unwrap()
     }
 
-    fn root_cert() -> &'static str {
-        include_str!("../../static/beam/ccp.root.crt.pem")
+    fn root_cert() -> Cow<'static, str> {
+        include_str!("../../static/beam/ccp.root.crt.pem").into()
     }
 }
 
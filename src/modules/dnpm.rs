@@ -23,12 +23,21 @@ pub enum DnpmConfig {
 }
 
 impl Module for Dnpm {
-    fn install(&self, service_map: &mut ServiceMap, global_conf: &'static Config) {
+    fn install(
+        &self,
+        service_map: &mut ServiceMap,
+        global_conf: &'static Config,
+    ) -> anyhow::Result<()> {
         let Some(conf) = &global_conf.dnpm else {
-            return;
+            return Ok(());
+        };
+        let config_key = match conf {
+            DnpmConfig::Node(_) => "dnpm.node",
+            DnpmConfig::Local { .. } => "dnpm.local",
         };
         let bc = service_map
             .install_default::<BeamConnect<CcpDefault>>()
+            .config_key(config_key)
             .post_install(|bc| {
                 for (vhost, beam_connect) in DNPM_SITES {
                     bc.add_central_target(CentralTarget {
@@ -37,10 +46,7 @@ impl Module for Dnpm {
                     });
                 }
             });
-        let default_allowed_remotes = DNPM_SITES
-            .into_iter()
-            .map(|(_, bc)| bc.to_string())
-            .collect();
+        let default_allowed_remotes = DNPM_SITES.iter().map(|(_, bc)| bc.to_string()).collect();
         match conf {
             DnpmConfig::Node(conf) => {
                 bc.post_install(|bc| {
@@ -50,7 +56,9 @@ impl Module for Dnpm {
                         default_allowed_remotes,
                     ));
                 });
-                service_map.install_with_config::<DnpmNode>((conf.clone(), global_conf));
+                service_map
+                    .install_with_config::<DnpmNode>((conf.clone(), global_conf))
+                    .config_key(config_key);
             }
             DnpmConfig::Local { target, no_proxy } => {
                 let mut target = target.clone();
@@ -65,6 +73,11 @@ impl Module for Dnpm {
                 });
             }
         }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "dnpm"
     }
 }
 
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -29,10 +31,21 @@ pub enum EucaimEndpointType {
 }
 
 impl Module for Eucaim {
-    fn install(&self, service_map: &mut ServiceMap, global_conf: &'static Config) {
+    fn install(
+        &self,
+        service_map: &mut ServiceMap,
+        global_conf: &'static Config,
+    ) -> anyhow::Result<()> {
         if let Some(eucaim_config) = global_conf.eucaim.clone() {
-            service_map.install_with_config::<Focus<Eucaim, EucaimEndpointType>>(eucaim_config);
+            service_map
+                .install_with_config::<Focus<Eucaim, EucaimEndpointType>>(eucaim_config)
+                .config_key("eucaim");
         }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "eucaim"
     }
 }
 
@@ -45,7 +58,7 @@ impl BrokerProvider for Eucaim {
         "eucaim"
     }
 
-    fn root_cert() -> &'static str {
-        include_str!("../../static/beam/eucaim.root.crt.pem")
+    fn root_cert() -> Cow<'static, str> {
+        include_str!("../../static/beam/eucaim.root.crt.pem").into()
     }
 }
@@ -1,21 +1,30 @@
 mod bbmri;
+mod brokers;
 mod ccp;
 mod dnpm;
 mod eucaim;
 
 use crate::{Config, services::ServiceMap};
 pub use bbmri::BbmriConfig;
+pub use brokers::BrokersConfig;
 pub use ccp::{CcpConfig, CcpDefault};
 pub use dnpm::DnpmConfig;
 pub use eucaim::{EucaimConfig, EucaimEndpointType};
 
 pub trait Module {
-    fn install(&self, service_map: &mut ServiceMap, conf: &'static Config);
+    fn install(&self, service_map: &mut ServiceMap, conf: &'static Config) -> anyhow::Result<()>;
+
+    /// Name of the module which is also the name of its section in config.toml
+    fn name(&self) -> &'static str;
 }
 
 impl Module for &dyn Module {
-    fn install(&self, service_map: &mut ServiceMap, conf: &'static Config) {
-        (*self).install(service_map, conf);
+    fn install(&self, service_map: &mut ServiceMap, conf: &'static Config) -> anyhow::Result<()> {
+        (*self).install(service_map, conf)
+    }
+
+    fn name(&self) -> &'static str {
+        (*self).name()
     }
 }
 
@@ -24,4 +33,5 @@ pub const MODULES: &[&dyn Module] = &[
     &bbmri::Bbmri,
     &dnpm::Dnpm,
     &eucaim::Eucaim,
+    &brokers::Brokers,
 ];
@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{config::Config, enroll, services::BEAM_NETWORKS};
+
+/// Written to the config directory after every update
+pub const UPDATE_REPORT: &str = "update-report.json";
+
+/// Generated files an update report is computed from
+#[derive(Debug, Default)]
+pub struct GeneratedState {
+    /// Compose files in `services/` keyed by service name
+    services: BTreeMap<String, String>,
+    /// Locked image of each compose service in docker-image.lock.yml
+    images: BTreeMap<String, String>,
+    /// Files in `secrets/` keyed by variable name
+    secret_files: BTreeMap<String, String>,
+    /// All variables of `.env` as only some of them are generated secrets
+    env: BTreeMap<String, String>,
+}
+
+impl GeneratedState {
+    pub fn capture(path: &Path) -> anyhow::Result<Self> {
+        let services = read_dir(&path.join("services"))?
+            .into_iter()
+            .filter_map(|(file, content)| Some((file.strip_suffix(".yml")?.to_string(), content)))
+            .collect();
+        let env = read(&path.join(".env"))?
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Ok(Self {
+            services,
+            images: read_lockfile(&path.join("docker-image.lock.yml"))?,
+            secret_files: read_dir(&path.join("secrets"))?,
+            env,
+        })
+    }
+
+    /// Generated secrets keyed by variable name. `generated` are the secret names of the generated config
+    /// as the `.env` holds e.g. the OIDC settings as well.
+    fn secrets(&self, generated: &BTreeSet<String>) -> BTreeMap<String, String> {
+        let env = self
+            .env
+            .iter()
+            .filter(|(name, _)| generated.contains(*name))
+            .map(|(name, value)| (name.clone(), value.clone()));
+        self.secret_files.clone().into_iter().chain(env).collect()
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Contents of all files in `dir` keyed by file name
+fn read_dir(dir: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut files = BTreeMap::new();
+    for entry in entries {
+        let entry = entry?;
+        if let (Some(content), Some(name)) = (read(&entry.path())?, entry.file_name().to_str()) {
+            files.insert(name.to_string(), content);
+        }
+    }
+    Ok(files)
+}
+
+fn read_lockfile(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let Some(lockfile) = read(path)? else {
+        return Ok(BTreeMap::new());
+    };
+    let lockfile: serde_yaml_ng::Value = serde_yaml_ng::from_str(&lockfile)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let Some(services) = lockfile.get("services").and_then(|s| s.as_mapping()) else {
+        return Ok(BTreeMap::new());
+    };
+    Ok(services
+        .iter()
+        .filter_map(|(name, service)| {
+            Some((
+                name.as_str()?.to_string(),
+                service.get("image")?.as_str()?.to_string(),
+            ))
+        })
+        .collect())
+}
+
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl Changes {
+    fn between(before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) -> Self {
+        let mut changes = Self::default();
+        let names: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+        for name in names {
+            match (before.get(name), after.get(name)) {
+                (None, Some(_)) => changes.added.push(name.clone()),
+                (Some(_), None) => changes.removed.push(name.clone()),
+                (Some(before), Some(after)) if before != after => {
+                    changes.changed.push(name.clone())
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ImageChange {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateReport {
+    pub site_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// Generated compose files by service name
+    pub services: Changes,
+    /// Locked images keyed by compose service name
+    pub images: BTreeMap<String, ImageChange>,
+    /// Names of the generated secrets. Their values are never reported.
+    pub secrets: Changes,
+    /// Reasons why beam networks still need to be enrolled keyed by broker id
+    pub unenrolled_networks: BTreeMap<String, String>,
+    /// Same as `update` exiting with code 3
+    pub restart_required: bool,
+}
+
+impl UpdateReport {
+    pub fn new(
+        conf: &Config,
+        before: &GeneratedState,
+        after: &GeneratedState,
+        restart_required: bool,
+    ) -> Self {
+        let image_changes = Changes::between(&before.images, &after.images);
+        let images = [
+            image_changes.added,
+            image_changes.removed,
+            image_changes.changed,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|service| {
+            let change = ImageChange {
+                before: before.images.get(&service).cloned(),
+                after: after.images.get(&service).cloned(),
+            };
+            (service, change)
+        })
+        .collect();
+        let beam_networks = BEAM_NETWORKS.with_borrow(|nets| nets.keys().cloned().collect());
+        // Only known once the services have been generated
+        let generated: BTreeSet<_> = conf
+            .local_conf
+            .borrow()
+            .generated_secrets
+            .keys()
+            .cloned()
+            .collect();
+        Self {
+            site_id: conf.site_id.clone(),
+            time: OffsetDateTime::now_utc(),
+            services: Changes::between(&before.services, &after.services),
+            images,
+            secrets: Changes::between(&before.secrets(&generated), &after.secrets(&generated)),
+            unenrolled_networks: enroll::enrollment_problems(conf, &beam_networks),
+            restart_required,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let report_path = path.join(UPDATE_REPORT);
+        fs::write(&report_path, self.to_json()?)
+            .with_context(|| format!("Failed to write {}", report_path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_report() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        fs::write(
+            path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        let update = || {
+            let mut services = crate::load_services(&path).unwrap();
+            let before = GeneratedState::capture(&path).unwrap();
+            services.write_generated_files().unwrap();
+            let after = GeneratedState::capture(&path).unwrap();
+            UpdateReport::new(services.config(), &before, &after, false)
+        };
+        let first = update();
+        assert!(!first.secrets.added.is_empty());
+        assert!(first.services.added.contains(&"ccp-exporter".to_string()));
+
+        // OIDC settings are not generated so they do not count as secrets
+        let env = fs::read_to_string(path.join(".env")).unwrap();
+        fs::write(
+            path.join(".env"),
+            format!("{env}OIDC_CLIENT_ID=\"client\"\n"),
+        )
+        .unwrap();
+        let unchanged = update();
+        assert_eq!(unchanged.services, Changes::default());
+        assert_eq!(unchanged.secrets, Changes::default());
+
+        // e.g. edited by hand and restored by the update
+        let env = fs::read_to_string(path.join(".env")).unwrap();
+        let (secret, _) = env
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(name, _)| !name.starts_with("OIDC"))
+            .unwrap();
+        fs::write(path.join(".env"), format!("{env}{secret}=\"edited\"\n")).unwrap();
+        let report = update();
+        assert_eq!(
+            report.secrets,
+            Changes {
+                changed: vec![secret.to_string()],
+                ..Default::default()
+            }
+        );
+        let json = report.to_json().unwrap();
+        assert!(!json.contains("edited"), "{json}");
+        for (_, value) in env.lines().filter_map(|line| line.split_once('=')) {
+            assert!(!json.contains(value), "{json}");
+        }
+    }
+
+    #[test]
+    fn test_image_changes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        fs::write(
+            path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n",
+        )
+        .unwrap();
+        let conf = Config::load(&path).unwrap();
+        let lockfile = |traefik: &str| {
+            format!(
+                "services:\n  traefik:\n    image: traefik@sha256:{traefik}\n  forward_proxy:\n    image: squid@sha256:1\n"
+            )
+        };
+        fs::write(path.join("docker-image.lock.yml"), lockfile("1")).unwrap();
+        let before = GeneratedState::capture(&path).unwrap();
+        fs::write(path.join("docker-image.lock.yml"), lockfile("2")).unwrap();
+        let after = GeneratedState::capture(&path).unwrap();
+        let report = UpdateReport::new(&conf, &before, &after, true);
+        assert_eq!(
+            report.images,
+            BTreeMap::from([(
+                "traefik".to_string(),
+                ImageChange {
+                    before: Some("traefik@sha256:1".into()),
+                    after: Some("traefik@sha256:2".into()),
+                }
+            )])
+        );
+        assert!(report.unenrolled_networks.is_empty());
+    }
+}
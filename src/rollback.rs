@@ -0,0 +1,237 @@
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path};
+
+use anyhow::Context;
+use git2::{IndexAddOption, build::CheckoutBuilder};
+
+use crate::{dry_run, git};
+
+/// Files written by an update that get restored from the target commit
+const RESTORED_FILES: &[&str] = &[
+    "services",
+    "bridgehead",
+    "docker-image.lock.yml",
+    "config.local.toml.age",
+];
+
+/// Files that get regenerated to match the restored state
+const REGENERATED_FILES: &[&str] = &[
+    ".env",
+    "secrets",
+    "config.local.toml",
+    "config.local.toml.age",
+];
+
+pub struct Rollback {
+    /// Full hash of the commit that was restored
+    pub commit: String,
+    /// Whether any generated file changed
+    pub changed: bool,
+}
+
+/// Restores the generated files of `target` and regenerates the private files from the config.toml
+/// and config.local.toml of that commit. The current config.toml is kept and the rollback is committed.
+pub fn rollback(conf_path: &Path, target: &str) -> anyhow::Result<Rollback> {
+    // Same as for updates so group members keep write access to the shared repository
+    unsafe { libc::umask(0o0002) };
+    let repo = git::open(conf_path)?.context("Bridgehead directory is not a git repository")?;
+    let commit = repo
+        .revparse_single(target)
+        .and_then(|object| object.peel_to_commit())
+        .with_context(|| format!("{target} is not a commit of the bridgehead repository"))?;
+    let tree = commit.tree()?;
+    let blob = |file: &str| -> anyhow::Result<Option<git2::Blob>> {
+        match tree.get_path(Path::new(file)) {
+            Ok(entry) => Ok(Some(entry.to_object(&repo)?.peel_to_blob()?)),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    };
+    let config_toml =
+        blob("config.toml")?.with_context(|| format!("{} has no config.toml", commit.id()))?;
+    let config_toml = std::str::from_utf8(config_toml.content())?;
+    // The private files are generated before anything gets replaced so a failure leaves the current files intact
+    let scratch = dry_run::scratch_copy(conf_path, Some(config_toml))?;
+    let encrypted_local_conf = scratch.path().join("config.local.toml.age");
+    match blob("config.local.toml.age")? {
+        Some(blob) => fs::write(&encrypted_local_conf, blob.content())?,
+        None => remove(&encrypted_local_conf)?,
+    }
+    dry_run::generate_in(scratch.path())
+        .with_context(|| format!("Failed to regenerate the private files of {}", commit.id()))?;
+    // Files generated after the commit have to go as well
+    for file in RESTORED_FILES {
+        remove(&conf_path.join(file))?;
+    }
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    let mut in_commit = false;
+    for file in RESTORED_FILES {
+        if tree.get_path(Path::new(file)).is_ok() {
+            checkout.path(file);
+            in_commit = true;
+        }
+    }
+    if in_commit {
+        repo.checkout_tree(commit.as_object(), Some(&mut checkout))
+            .with_context(|| format!("Failed to restore files of {}", commit.id()))?;
+    }
+    for file in REGENERATED_FILES {
+        remove(&conf_path.join(file))?;
+        dry_run::copy_recursive(&scratch.path().join(file), &conf_path.join(file))?;
+    }
+    let secrets_dir = conf_path.join("secrets");
+    if secrets_dir.exists() {
+        fs::set_permissions(&secrets_dir, fs::Permissions::from_mode(0o700))?;
+    }
+    let mut index = repo.index()?;
+    index.remove_all(RESTORED_FILES, None)?;
+    index.add_all(
+        RESTORED_FILES
+            .iter()
+            .filter(|file| conf_path.join(file).exists()),
+        IndexAddOption::DEFAULT,
+        None,
+    )?;
+    index.write()?;
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let changed =
+        git::diff_paths(&repo.diff_tree_to_index(Some(&head_tree), Some(&index), None)?);
+    if !changed.is_empty() {
+        let id = commit.id().to_string();
+        git::commit_index(
+            &repo,
+            &format!(
+                "Rolled back to {}\n\n{id} {}\n\n{}",
+                &id[..12],
+                commit.summary().ok().flatten().unwrap_or_default(),
+                changed.join("\n")
+            ),
+        )?;
+    }
+    Ok(Rollback {
+        commit: commit.id().to_string(),
+        changed: !changed.is_empty(),
+    })
+}
+
+/// Hash of the update `steps` updates before the latest one. Like `HEAD~<steps>` this follows the first parents
+/// but only counts commits of updates so config changes pulled from the remote or rollbacks are skipped.
+pub fn update_before(conf_path: &Path, steps: u32) -> anyhow::Result<String> {
+    let repo = git::open(conf_path)?.context("Bridgehead directory is not a git repository")?;
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.simplify_first_parent()?;
+    let mut updates = 0;
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        if !git::is_update_commit(&commit) {
+            continue;
+        }
+        if updates == steps {
+            return Ok(commit.id().to_string());
+        }
+        updates += 1;
+    }
+    anyhow::bail!(
+        "There are only {} updates before the latest one",
+        updates.saturating_sub(1)
+    )
+}
+
+fn remove(path: &Path) -> anyhow::Result<()> {
+    let res = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_and_commit(conf_path: &Path, config_toml: &str, message: &str) {
+        fs::write(conf_path.join("config.toml"), config_toml).unwrap();
+        crate::load_services(&conf_path.to_path_buf())
+            .unwrap()
+            .write_generated_files()
+            .unwrap();
+        git::run(conf_path, &["add", "-A"]).unwrap();
+        git::run(conf_path, &["commit", "-q", "-m", message]).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        let minimal = "site_id = \"dummy\"\nhostname = \"dummy.local\"\n";
+        let with_exporter = format!("{minimal}[ccp]\nexporter = {{}}\n");
+        git::run(&conf_path, &["init", "-q"]).unwrap();
+        git::run(&conf_path, &["config", "user.email", "test@example.org"]).unwrap();
+        git::run(&conf_path, &["config", "user.name", "Test"]).unwrap();
+        generate_and_commit(&conf_path, minimal, "Minimal");
+        let minimal_env = fs::read_to_string(conf_path.join(".env")).unwrap();
+        generate_and_commit(&conf_path, &with_exporter, "Exporter");
+        assert!(conf_path.join("services/ccp-exporter.yml").exists());
+
+        assert!(rollback(&conf_path, "HEAD~1").unwrap().changed);
+        assert!(!conf_path.join("services/ccp-exporter.yml").exists());
+        assert_eq!(
+            fs::read_to_string(conf_path.join(".env")).unwrap(),
+            minimal_env
+        );
+        // The config is left alone so the next update brings the exporter back
+        assert_eq!(
+            fs::read_to_string(conf_path.join("config.toml")).unwrap(),
+            with_exporter
+        );
+        let log = git::run(&conf_path, &["log", "-1", "--format=%s%n%b"]).unwrap();
+        assert!(log.starts_with("Rolled back to"), "{log}");
+        assert!(log.contains("services/ccp-exporter.yml"), "{log}");
+        assert!(
+            git::run(&conf_path, &["status", "--porcelain"])
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(!rollback(&conf_path, "HEAD~2").unwrap().changed);
+        assert!(rollback(&conf_path, "unknown").is_err());
+
+        // Nothing is replaced if the files of the commit can not be generated
+        fs::write(conf_path.join("config.toml"), "site_id = 1\n").unwrap();
+        git::run(&conf_path, &["commit", "-q", "-am", "Broken"]).unwrap();
+        generate_and_commit(&conf_path, &with_exporter, "Exporter");
+        assert!(rollback(&conf_path, "HEAD~1").is_err());
+        assert!(conf_path.join("services/ccp-exporter.yml").exists());
+        assert!(
+            git::run(&conf_path, &["status", "--porcelain"])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_update_before() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        let minimal = "site_id = \"dummy\"\nhostname = \"dummy.local\"\n";
+        git::run(&conf_path, &["init", "-q"]).unwrap();
+        git::run(&conf_path, &["config", "user.email", "test@example.org"]).unwrap();
+        git::run(&conf_path, &["config", "user.name", "Test"]).unwrap();
+        generate_and_commit(&conf_path, minimal, "Git files changed");
+        let first = git::run(&conf_path, &["rev-parse", "HEAD"]).unwrap();
+        generate_and_commit(&conf_path, &format!("{minimal}# pulled\n"), "Edit config");
+        generate_and_commit(&conf_path, minimal, "Nothing changed");
+        let latest = git::run(&conf_path, &["rev-parse", "HEAD"]).unwrap();
+
+        assert_eq!(update_before(&conf_path, 0).unwrap(), latest.trim());
+        assert_eq!(update_before(&conf_path, 1).unwrap(), first.trim());
+        assert!(update_before(&conf_path, 2).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use crate::{
+    config::{Config, LocalConf},
+    load_services,
+    services::compose::ComposeFile,
+};
+
+/// Generated secrets that changed
+#[derive(Debug)]
+pub struct Rotation {
+    /// Variable names of the rotated secrets
+    pub secrets: Vec<String>,
+    /// Compose services that use any of the rotated secrets
+    pub services: BTreeSet<String>,
+    /// Compose services that are databases which keep the old password in their volume
+    pub databases: BTreeSet<String>,
+}
+
+/// Rotates all generated secrets matching the filters and regenerates `.env`
+pub fn rotate(
+    conf_path: &PathBuf,
+    service: Option<&str>,
+    secret: Option<&str>,
+) -> anyhow::Result<Rotation> {
+    change_secrets(conf_path, |local_conf| {
+        let secrets = local_conf.rotate_secrets(service, secret);
+        anyhow::ensure!(
+            !secrets.is_empty(),
+            "No generated secret matches the given service and secret"
+        );
+        Ok(secrets)
+    })
+}
+
+/// Switches from the legacy seed to a master key which changes every generated secret
+pub fn migrate(conf_path: &PathBuf) -> anyhow::Result<Rotation> {
+    change_secrets(conf_path, |local_conf| {
+        anyhow::ensure!(
+            local_conf.migrate_to_master_key(),
+            "Secrets are already derived from a master key"
+        );
+        Ok(local_conf.generated_secrets.keys().cloned().collect())
+    })
+}
+
+/// Applies `change` to the local config after generating all secrets once and regenerates `.env` afterwards
+fn change_secrets(
+    conf_path: &PathBuf,
+    change: impl FnOnce(&mut LocalConf) -> anyhow::Result<Vec<String>>,
+) -> anyhow::Result<Rotation> {
+    let (conf, _) = generate(conf_path)?;
+    let secrets = change(&mut conf.local_conf.borrow_mut())?;
+    conf.write_local_conf()?;
+    // Regenerate from the updated local config so the rotated secrets get derived anew
+    let (conf, files) = generate(conf_path)?;
+    conf.write_local_conf()?;
+    let mut rotation = Rotation {
+        secrets,
+        services: BTreeSet::new(),
+        databases: BTreeSet::new(),
+    };
+    for (name, service) in files.values().flat_map(|f| &f.services) {
+        let uses_rotated = service
+            .referenced_vars()
+            .iter()
+            .map(String::as_str)
+            .chain(service.sources("secrets"))
+            .any(|var| rotation.secrets.iter().any(|s| s == var));
+        if !uses_rotated {
+            continue;
+        }
+        let image = service.image.as_deref().unwrap_or_default();
+        if ["postgres", "mysql", "mariadb"]
+            .iter()
+            .any(|db| image.contains(db))
+        {
+            rotation.databases.insert(name.clone());
+        }
+        rotation.services.insert(name.clone());
+    }
+    Ok(rotation)
+}
+
+fn generate(
+    conf_path: &PathBuf,
+) -> anyhow::Result<(&'static Config, BTreeMap<String, ComposeFile>)> {
+    let mut services = load_services(conf_path)?;
+    let files = services.compose()?;
+    Ok((services.config(), files))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn env_var(conf_path: &std::path::Path, var: &str) -> String {
+        fs::read_to_string(conf_path.join(".env"))
+            .unwrap()
+            .lines()
+            .find_map(|l| l.strip_prefix(&format!("{var}=")).map(str::to_string))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rotate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        let (conf, _) = generate(&conf_path).unwrap();
+        conf.write_local_conf().unwrap();
+        let api_key = env_var(&conf_path, "CCP_EXPORTER_API_KEY");
+        let db_password = env_var(&conf_path, "CCP_EXPORTER_DB_PASSWORD");
+
+        let rotation = rotate(&conf_path, Some("ccp-exporter"), Some("api-key")).unwrap();
+        assert_eq!(rotation.secrets, ["CCP_EXPORTER_API_KEY"]);
+        assert!(rotation.services.contains("ccp-exporter"));
+        assert!(rotation.services.contains("ccp-focus"));
+        assert!(rotation.databases.is_empty());
+        assert_ne!(env_var(&conf_path, "CCP_EXPORTER_API_KEY"), api_key);
+        assert_eq!(env_var(&conf_path, "CCP_EXPORTER_DB_PASSWORD"), db_password);
+
+        let rotation = rotate(&conf_path, Some("ccp-exporter-db"), None).unwrap();
+        assert_eq!(
+            rotation.databases,
+            BTreeSet::from(["ccp-exporter-db".into()])
+        );
+        assert!(rotate(&conf_path, Some("nonexistent"), None).is_err());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let conf_path = temp_dir.path().to_path_buf();
+        fs::write(
+            conf_path.join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        fs::write(conf_path.join("config.local.toml"), "seed = 42\n").unwrap();
+        let (conf, _) = generate(&conf_path).unwrap();
+        conf.write_local_conf().unwrap();
+        // Same value as in the snapshots generated with the legacy seed
+        assert_eq!(
+            env_var(&conf_path, "CCP_EXPORTER_API_KEY"),
+            "\"0RV9))l@tt\""
+        );
+
+        let rotation = migrate(&conf_path).unwrap();
+        assert!(rotation.databases.contains("ccp-exporter-db"));
+        let local_conf = fs::read_to_string(conf_path.join("config.local.toml")).unwrap();
+        assert!(local_conf.contains("master_key = "), "{local_conf}");
+        assert!(!local_conf.contains("seed"), "{local_conf}");
+        let api_key = env_var(&conf_path, "CCP_EXPORTER_API_KEY");
+        assert_ne!(api_key, "\"0RV9))l@tt\"");
+
+        // Derivation from the master key is stable
+        let (conf, _) = generate(&conf_path).unwrap();
+        conf.write_local_conf().unwrap();
+        assert_eq!(env_var(&conf_path, "CCP_EXPORTER_API_KEY"), api_key);
+        assert!(migrate(&conf_path).is_err());
+    }
+}
@@ -1,27 +1,29 @@
 use std::{
-    cell::RefCell,
-    collections::{BTreeMap, BTreeSet},
-    fs,
-    marker::PhantomData,
-    path::PathBuf,
+    borrow::Cow, cell::RefCell, collections::BTreeMap, fs, marker::PhantomData, path::PathBuf,
     str::FromStr,
 };
 
+use anyhow::Context;
 use askama::Template;
 use url::Url;
 
-use crate::{Config, config::LocalConf, utils::filters};
+use crate::{Config, config::LocalConf, enroll, utils::filters};
 
 use super::{Deps, ForwardProxy, Service};
 
 pub trait BrokerProvider: 'static {
     fn broker_url() -> Url;
     fn network_name() -> &'static str;
-    fn root_cert() -> &'static str;
+    fn root_cert() -> Cow<'static, str>;
 
     fn broker_id() -> String {
         Self::broker_url().host().unwrap().to_string()
     }
+
+    /// Fails if the broker is not available to create services for
+    fn check_configured() -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, Template)]
@@ -62,18 +64,40 @@ impl<T: BrokerProvider> Service for BeamProxy<T> {
     type Dependencies = (ForwardProxy,);
     type ServiceConfig = &'static Config;
 
-    fn from_config(conf: Self::ServiceConfig, (fw_proxy,): Deps<Self>) -> Self {
-        BEAM_NETWORKS.with_borrow_mut(|nets| nets.insert(T::broker_id()));
-        fs::create_dir_all(conf.path.join("pki")).unwrap();
-        BeamProxy {
+    fn from_config(conf: Self::ServiceConfig, (fw_proxy,): Deps<Self>) -> anyhow::Result<Self> {
+        T::check_configured()?;
+        BEAM_NETWORKS.with_borrow_mut(|nets| nets.insert(T::broker_id(), Self::service_name()));
+        let priv_key = prepare_priv_key_path(conf)?;
+        // Not existing yet is fine as the bridgehead script asks to enroll first
+        if priv_key.exists() {
+            crate::certs::check_private_key(&priv_key)?;
+            let enrolled = conf
+                .local_conf
+                .borrow()
+                .beam_enrollments
+                .as_ref()
+                .and_then(|enrollments| enrollments.get(&T::broker_id()))
+                .map(|enrollment| enrollment.key_fingerprint.clone());
+            // Keys ring can not parse are reported by the enrollment check of the bridgehead script
+            if let (Some(enrolled), Ok(current)) = (enrolled, enroll::key_fingerprint(&priv_key))
+                && enrolled != current
+            {
+                eprintln!(
+                    "Warning: {} is not the key the CSR for {} was generated for",
+                    priv_key.display(),
+                    T::broker_id()
+                );
+            }
+        }
+        Ok(BeamProxy {
             broker_provider: PhantomData,
-            priv_key: conf.path.join(format!("pki/{}.priv.pem", conf.site_id)),
+            priv_key,
             proxy_id: format!("{}.{}", conf.site_id, T::broker_id()),
             app_keys: Default::default(),
             fw_proxy_url: fw_proxy.get_url(),
-            trusted_ca_certs: conf.trusted_ca_certs(),
+            trusted_ca_certs: conf.trusted_ca_certs()?,
             local_conf: &conf.local_conf,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -81,6 +105,22 @@ impl<T: BrokerProvider> Service for BeamProxy<T> {
     }
 }
 
+/// Private key shared by the beam proxies of all networks
+pub fn priv_key_path(conf: &Config) -> PathBuf {
+    conf.path
+        .join("pki")
+        .join(format!("{}.priv.pem", conf.site_id))
+}
+
+/// Same as [`priv_key_path`] but creates the pki directory if needed
+pub fn prepare_priv_key_path(conf: &Config) -> anyhow::Result<PathBuf> {
+    let priv_key = priv_key_path(conf);
+    let pki_dir = priv_key.parent().unwrap();
+    fs::create_dir_all(pki_dir).with_context(|| format!("Failed to create {pki_dir:?}"))?;
+    Ok(priv_key)
+}
+
 thread_local! {
-    pub static BEAM_NETWORKS: RefCell<BTreeSet<String>> = RefCell::default();
+    /// Service name of the beam proxy of every created network keyed by broker id
+    pub static BEAM_NETWORKS: RefCell<BTreeMap<String, String>> = RefCell::default();
 }
@@ -10,7 +10,6 @@ use crate::{
 
 #[derive(Template)]
 #[template(path = "beam_connect.yml")]
-
 pub struct BeamConnect<T>
 where
     Self: Service,
@@ -28,16 +27,16 @@ impl<T: BrokerProvider> Service for BeamConnect<T> {
 
     type ServiceConfig = &'static Config;
 
-    fn from_config(conf: Self::ServiceConfig, (beam,): super::Deps<Self>) -> Self {
+    fn from_config(conf: Self::ServiceConfig, (beam,): super::Deps<Self>) -> anyhow::Result<Self> {
         let beam = beam.add_service("beam-connect");
-        Self {
+        Ok(Self {
             beam,
-            trusted_ca_certs: conf.trusted_ca_certs(),
+            trusted_ca_certs: conf.trusted_ca_certs()?,
             local_targets: vec![],
             central_targets: vec![],
             no_proxy: vec![],
             beam_provider: PhantomData,
-        }
+        })
     }
 
     fn service_name() -> String {
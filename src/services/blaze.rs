@@ -3,9 +3,9 @@ use std::{marker::PhantomData, str::FromStr};
 use askama::Template;
 use url::Url;
 
-use crate::utils::filters;
+use crate::{config::Config, utils::filters};
 
-use super::{Service, Traefik};
+use super::{Service, Traefik, TraefikAuth};
 
 #[derive(Debug, Template)]
 #[template(path = "blaze.yml")]
@@ -28,17 +28,20 @@ where
 
 impl<T: BlazeProvider> Service for Blaze<T> {
     type Dependencies = (Traefik,);
-    type ServiceConfig = ();
+    type ServiceConfig = &'static Config;
 
-    fn from_config(_conf: Self::ServiceConfig, (traefik,): super::Deps<Self>) -> Self {
-        let traefik_conf = T::treafik_exposure();
-        if let Some(conf) = &traefik_conf {
-            traefik.add_basic_auth_user(conf.middleware_and_user_name.clone())
+    fn from_config(
+        conf: Self::ServiceConfig,
+        (traefik,): super::Deps<Self>,
+    ) -> anyhow::Result<Self> {
+        let traefik_conf = T::treafik_exposure(conf);
+        if let Some(traefik_conf) = &traefik_conf {
+            traefik.add_auth(&traefik_conf.auth)?;
         }
-        Self {
+        Ok(Self {
             r#for: PhantomData,
             traefik_conf,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -50,7 +53,7 @@ pub trait BlazeProvider: 'static {
     fn balze_service_name() -> String;
 
     /// relative path where this balze should be exposed through traefik. Defaults to None
-    fn treafik_exposure() -> Option<BlazeTraefikConfig> {
+    fn treafik_exposure(_conf: &Config) -> Option<BlazeTraefikConfig> {
         None
     }
 }
@@ -58,7 +61,7 @@ pub trait BlazeProvider: 'static {
 #[derive(Debug)]
 pub struct BlazeTraefikConfig {
     pub path: String,
-    pub middleware_and_user_name: String,
+    pub auth: TraefikAuth,
 }
 
 impl<T: Service> BlazeProvider for T {
@@ -0,0 +1,651 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path,
+};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serialize, de};
+use serde_yaml_ng::{Mapping, Value};
+
+/// A docker compose file as produced by a single `Service`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ComposeFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Top level `x-*` keys which compose ignores but which are allowed to hold yaml anchors
+    #[serde(flatten, deserialize_with = "extensions")]
+    pub extensions: IndexMap<String, Value>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub services: IndexMap<String, ComposeService>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub volumes: IndexMap<String, Option<Mapping>>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub configs: IndexMap<String, Option<Mapping>>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub secrets: IndexMap<String, Option<Mapping>>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub networks: IndexMap<String, Option<Mapping>>,
+}
+
+/// Rejects every remaining top level key that is not an `x-*` extension to catch typos in templates
+fn extensions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<IndexMap<String, Value>, D::Error> {
+    let extensions = IndexMap::<String, Value>::deserialize(deserializer)?;
+    if let Some(key) = extensions.keys().find(|k| !k.starts_with("x-")) {
+        return Err(de::Error::unknown_field(
+            key,
+            &[
+                "name", "services", "volumes", "configs", "secrets", "networks", "x-*",
+            ],
+        ));
+    }
+    Ok(extensions)
+}
+
+/// A compose service keeping the key order of the rendered template when serialized
+#[derive(Debug, Default)]
+pub struct ComposeService {
+    pub image: Option<String>,
+    pub environment: KeyValues,
+    pub volumes: Vec<Value>,
+    pub ports: Vec<Value>,
+    pub labels: KeyValues,
+    /// Everything this model does not need to inspect is passed through unchanged
+    pub other: IndexMap<String, Value>,
+    /// Keys in the order they were deserialized in. Keys added afterwards follow them.
+    key_order: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for ComposeService {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut other = IndexMap::<String, Value>::deserialize(deserializer)?;
+        let key_order = other.keys().cloned().collect();
+        fn take<T: Default + de::DeserializeOwned, E: de::Error>(
+            other: &mut IndexMap<String, Value>,
+            key: &str,
+        ) -> Result<T, E> {
+            match other.shift_remove(key) {
+                Some(value) => serde_yaml_ng::from_value(value)
+                    .map_err(|e| E::custom(format!("invalid {key}: {e}"))),
+                None => Ok(T::default()),
+            }
+        }
+        Ok(Self {
+            image: take(&mut other, "image")?,
+            environment: take(&mut other, "environment")?,
+            volumes: take(&mut other, "volumes")?,
+            ports: take(&mut other, "ports")?,
+            labels: take(&mut other, "labels")?,
+            other,
+            key_order,
+        })
+    }
+}
+
+impl Serialize for ComposeService {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let typed = ["image", "environment", "volumes", "ports", "labels"];
+        let keys: IndexMap<&str, ()> = self
+            .key_order
+            .iter()
+            .map(String::as_str)
+            .chain(typed)
+            .chain(self.other.keys().map(String::as_str))
+            .map(|k| (k, ()))
+            .collect();
+        let mut map = serializer.serialize_map(None)?;
+        for key in keys.keys().copied() {
+            match key {
+                "image" if self.image.is_some() => map.serialize_entry(key, &self.image)?,
+                "environment" if !self.environment.is_empty() => {
+                    map.serialize_entry(key, &self.environment)?
+                }
+                "volumes" if !self.volumes.is_empty() => map.serialize_entry(key, &self.volumes)?,
+                "ports" if !self.ports.is_empty() => map.serialize_entry(key, &self.ports)?,
+                "labels" if !self.labels.is_empty() => map.serialize_entry(key, &self.labels)?,
+                // Typed fields never end up in `other`
+                _ => {
+                    if let Some(value) = self.other.get(key) {
+                        map.serialize_entry(key, value)?;
+                    }
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+fn is_path(source: &str) -> bool {
+    source.starts_with(['/', '.', '~', '$'])
+}
+
+/// Environment variables or labels which compose accepts either as a list of `KEY=value` or as a mapping
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct KeyValues(pub IndexMap<String, Option<String>>);
+
+impl KeyValues {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(key.into(), Some(value.into()));
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyValues {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let scalar = |v: Value| match v {
+            Value::Null => Ok(None),
+            Value::String(s) => Ok(Some(s)),
+            Value::Bool(b) => Ok(Some(b.to_string())),
+            Value::Number(n) => Ok(Some(n.to_string())),
+            other => Err(de::Error::custom(format!(
+                "expected a scalar got {other:?}"
+            ))),
+        };
+        match Value::deserialize(deserializer)? {
+            Value::Sequence(list) => list
+                .into_iter()
+                .map(|entry| {
+                    let Some(entry) = entry.as_str() else {
+                        return Err(de::Error::custom("expected a list of strings"));
+                    };
+                    Ok(match entry.split_once('=') {
+                        Some((k, v)) => (k.to_string(), Some(v.to_string())),
+                        None => (entry.to_string(), None),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(Self),
+            Value::Mapping(map) => map
+                .into_iter()
+                .map(|(k, v)| {
+                    let Some(k) = k.as_str() else {
+                        return Err(de::Error::custom("expected string keys"));
+                    };
+                    Ok((k.to_string(), scalar(v)?))
+                })
+                .collect::<Result<_, _>>()
+                .map(Self),
+            Value::Null => Ok(Self::default()),
+            other => Err(de::Error::custom(format!(
+                "expected a list or a mapping got {other:?}"
+            ))),
+        }
+    }
+}
+
+impl ComposeService {
+    /// Variables this service needs to be defined in `.env`
+    pub fn referenced_vars(&self) -> BTreeSet<String> {
+        serde_yaml_ng::to_value(self)
+            .map(|value| vars_in(&value))
+            .unwrap_or_default()
+    }
+
+    /// Names of all named volumes (as opposed to bind mounts) used by this service
+    pub fn named_volumes(&self) -> impl Iterator<Item = &str> {
+        self.volumes.iter().filter_map(|volume| match volume {
+            Value::String(short) => short
+                .split_once(':')
+                .map(|(source, _)| source)
+                .filter(|source| !is_path(source)),
+            Value::Mapping(_) if volume["type"] == "volume" => volume["source"].as_str(),
+            _ => None,
+        })
+    }
+
+    /// Host paths mounted into this service skipping paths relative to the home directory or containing variables
+    pub fn bind_sources(&self) -> impl Iterator<Item = &str> {
+        self.volumes
+            .iter()
+            .filter_map(|volume| match volume {
+                Value::String(short) => short
+                    .split_once(':')
+                    .map(|(source, _)| source)
+                    .filter(|source| is_path(source)),
+                Value::Mapping(_) if volume["type"] == "bind" => volume["source"].as_str(),
+                _ => None,
+            })
+            .filter(|source| !source.starts_with('~') && !source.contains('$'))
+    }
+
+    /// Sources of the `configs` or `secrets` used by this service
+    pub fn sources(&self, key: &str) -> Vec<&str> {
+        let Some(Value::Sequence(entries)) = self.other.get(key) else {
+            return Vec::new();
+        };
+        entries
+            .iter()
+            .filter_map(|entry| entry.as_str().or_else(|| entry["source"].as_str()))
+            .collect()
+    }
+
+    /// Services this service depends on
+    pub fn depends_on(&self) -> Vec<&str> {
+        match self.other.get("depends_on") {
+            Some(Value::Sequence(list)) => list.iter().filter_map(Value::as_str).collect(),
+            Some(Value::Mapping(map)) => map.keys().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Host ports published by this service as `(host_ip, port)` with an empty ip meaning all interfaces
+    pub fn published_ports(&self) -> Vec<(String, String)> {
+        self.ports
+            .iter()
+            .filter_map(|port| match port {
+                Value::String(short) => {
+                    let short = short.split_once('/').map_or(short.as_str(), |(p, _)| p);
+                    let (host, _container) = short.rsplit_once(':')?;
+                    Some(match host.rsplit_once(':') {
+                        Some((ip, port)) => (ip.to_string(), port.to_string()),
+                        None => (String::new(), host.to_string()),
+                    })
+                }
+                Value::Mapping(_) => {
+                    let published = match &port["published"] {
+                        Value::Number(n) => n.to_string(),
+                        Value::String(s) => s.clone(),
+                        _ => return None,
+                    };
+                    let ip = port["host_ip"].as_str().unwrap_or_default();
+                    Some((ip.to_string(), published))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Names of all traefik http routers configured through labels
+    pub fn traefik_routers(&self) -> BTreeSet<&str> {
+        self.labels
+            .keys()
+            .filter_map(|k| k.strip_prefix("traefik.http.routers.")?.split_once('.'))
+            .map(|(router, _)| router)
+            .collect()
+    }
+}
+
+/// Problems in the generated compose files that docker compose would reject or that would break at runtime
+#[derive(Debug)]
+pub struct InvalidCompose(pub Vec<String>);
+
+impl fmt::Display for InvalidCompose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Generated compose files are invalid:\n  {}",
+            self.0.join("\n  ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidCompose {}
+
+/// Checks the compose files of all services (keyed by file name) for definitions that would clash once merged by docker compose
+pub fn check_conflicts(files: &BTreeMap<String, ComposeFile>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut services = BTreeMap::new();
+    let mut volumes = BTreeMap::new();
+    let mut routers = BTreeMap::new();
+    let mut ports: Vec<((String, String), &str)> = Vec::new();
+    for (file, compose) in files {
+        for volume in compose.volumes.keys() {
+            if let Some(other) = volumes.insert(volume, file) {
+                problems.push(format!(
+                    "volume {volume} is defined by both {other} and {file}"
+                ));
+            }
+        }
+        for (name, service) in &compose.services {
+            if let Some(other) = services.insert(name, file) {
+                problems.push(format!(
+                    "service {name} is defined by both {other} and {file}"
+                ));
+            }
+            for router in service.traefik_routers() {
+                if let Some(other) = routers.insert(router, name) {
+                    problems.push(format!(
+                        "traefik router {router} is used by both {other} and {name}"
+                    ));
+                }
+            }
+            for (ip, port) in service.published_ports() {
+                let conflict = ports.iter().find(|((other_ip, other_port), _)| {
+                    *other_port == port && (ip.is_empty() || other_ip.is_empty() || *other_ip == ip)
+                });
+                if let Some((_, other)) = conflict {
+                    problems.push(format!(
+                        "port {port} is published by both {other} and {name}"
+                    ));
+                }
+                ports.push(((ip, port), name));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks that everything referenced by a service is defined somewhere.
+/// `env` is the content of the `.env` file and `conf_dir` the directory containing it.
+/// Missing `synced_vars` only produce a warning as they are filled in by a sync that gets retried on the next update.
+pub fn check_references(
+    files: &BTreeMap<String, ComposeFile>,
+    env: &str,
+    synced_vars: &BTreeSet<String>,
+    conf_dir: &Path,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let defined_vars: BTreeSet<_> = env
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| Some(l.split_once('=')?.0.trim()))
+        .collect();
+    let all = |f: fn(&ComposeFile) -> Vec<&str>| -> BTreeSet<&str> {
+        files.values().flat_map(f).collect()
+    };
+    let services = all(|f| f.services.keys().map(String::as_str).collect());
+    let volumes = all(|f| f.volumes.keys().map(String::as_str).collect());
+    let configs = all(|f| f.configs.keys().map(String::as_str).collect());
+    let secrets = all(|f| f.secrets.keys().map(String::as_str).collect());
+    // Compose resolves relative paths against the directory of the first compose file
+    let project_dir = conf_dir.join("services");
+    for (file, compose) in files {
+        match serde_yaml_ng::to_value(compose) {
+            Ok(value) => {
+                for var in vars_in(&value) {
+                    if defined_vars.contains(var.as_str()) {
+                        continue;
+                    }
+                    if synced_vars.contains(&var) {
+                        eprintln!(
+                            "Warning: {file} references ${{{var}}} which has not been synced"
+                        );
+                    } else {
+                        problems.push(format!("{file} references undefined variable ${{{var}}}"));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("{file} can not be serialized: {e}")),
+        }
+        for (name, service) in &compose.services {
+            for volume in service.named_volumes() {
+                if !volumes.contains(volume) {
+                    problems.push(format!("{name} uses undeclared volume {volume}"));
+                }
+            }
+            for config in service.sources("configs") {
+                if !configs.contains(config) {
+                    problems.push(format!("{name} uses undeclared config {config}"));
+                }
+            }
+            for secret in service.sources("secrets") {
+                if !secrets.contains(secret) {
+                    problems.push(format!("{name} uses undeclared secret {secret}"));
+                }
+            }
+            for dep in service.depends_on() {
+                if !services.contains(dep) {
+                    problems.push(format!("{name} depends on unknown service {dep}"));
+                }
+            }
+            for source in service.bind_sources() {
+                let path = project_dir.join(source);
+                if path.exists() {
+                    continue;
+                }
+                if path.starts_with(conf_dir) {
+                    problems.push(format!(
+                        "{name} mounts {} which does not exist",
+                        path.display()
+                    ));
+                } else {
+                    // Paths outside of the bridgehead like the docker socket may legitimately be missing on the machine generating the files
+                    eprintln!(
+                        "Warning: {name} mounts {} which does not exist",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Variables interpolated by compose that have no default value
+fn vars_in(value: &Value) -> BTreeSet<String> {
+    let mut vars = BTreeSet::new();
+    let mut todo = vec![value];
+    while let Some(value) = todo.pop() {
+        match value {
+            Value::String(s) => vars.extend(interpolated_vars(s)),
+            Value::Sequence(list) => todo.extend(list),
+            Value::Mapping(map) => todo.extend(map.values()),
+            Value::Tagged(tagged) => todo.push(&tagged.value),
+            _ => {}
+        }
+    }
+    vars
+}
+
+fn interpolated_vars(s: &str) -> Vec<String> {
+    let is_var_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut vars = Vec::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        rest = &rest[i + 1..];
+        if let Some(escaped) = rest.strip_prefix('$') {
+            rest = escaped;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                break;
+            };
+            let expr = &braced[..end];
+            let name_end = expr.find(|c| !is_var_char(c)).unwrap_or(expr.len());
+            let modifier = expr[name_end..].trim_start_matches(':');
+            // `-` provides a default and `+` an alternative value so only `?` and plain references require the variable
+            if !modifier.starts_with(['-', '+']) {
+                vars.push(expr[..name_end].to_string());
+            }
+            rest = &braced[end + 1..];
+        } else {
+            let name_end = rest.find(|c| !is_var_char(c)).unwrap_or(rest.len());
+            if name_end > 0 {
+                vars.push(rest[..name_end].to_string());
+            }
+            rest = &rest[name_end..];
+        }
+    }
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> ComposeFile {
+        serde_yaml_ng::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_key_values() {
+        let compose = parse(
+            r#"
+services:
+  a:
+    environment:
+      - FOO=bar=baz
+      - PASSTHROUGH
+    labels:
+      traefik.enable: true
+"#,
+        );
+        let a = &compose.services["a"];
+        assert_eq!(a.environment.0["FOO"].as_deref(), Some("bar=baz"));
+        assert_eq!(a.environment.0["PASSTHROUGH"], None);
+        assert_eq!(a.labels.0["traefik.enable"].as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn test_key_order() {
+        let yaml = r#"name: bridgehead
+x-defaults:
+  restart: always
+services:
+  a:
+    image: a
+    command:
+    - run
+    environment:
+      FOO: bar
+    restart: always
+    labels:
+      traefik.enable: 'true'
+    depends_on:
+    - b
+"#;
+        let mut compose = parse(yaml);
+        assert_eq!(serde_yaml_ng::to_string(&compose).unwrap(), yaml);
+        let a = compose.services.get_mut("a").unwrap();
+        a.volumes.push("data:/data".into());
+        let serialized = serde_yaml_ng::to_string(&compose).unwrap();
+        assert!(
+            serialized.ends_with("    - b\n    volumes:\n    - data:/data\n"),
+            "{serialized}"
+        );
+    }
+
+    #[test]
+    fn test_unknown_top_level_key() {
+        let err = serde_yaml_ng::from_str::<ComposeFile>("service: {}\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `service`"), "{err}");
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let files = BTreeMap::from([
+            (
+                "a".to_string(),
+                parse(
+                    r#"
+services:
+  a:
+    ports:
+      - 80:80
+    labels:
+      - traefik.http.routers.a.rule=PathPrefix(`/a`)
+volumes:
+  data:
+"#,
+                ),
+            ),
+            (
+                "b".to_string(),
+                parse(
+                    r#"
+services:
+  b:
+    ports:
+      - 127.0.0.1:80:8080
+      - 443:443/tcp
+    labels:
+      - traefik.http.routers.a.rule=PathPrefix(`/b`)
+      - traefik.http.routers.a.tls=true
+volumes:
+  data:
+"#,
+                ),
+            ),
+        ]);
+        let err = check_conflicts(&files).join("\n");
+        assert!(
+            err.contains("volume data is defined by both a and b"),
+            "{err}"
+        );
+        assert!(
+            err.contains("traefik router a is used by both a and b"),
+            "{err}"
+        );
+        assert!(
+            err.contains("port 80 is published by both a and b"),
+            "{err}"
+        );
+        assert!(!err.contains("443"), "{err}");
+    }
+
+    #[test]
+    fn test_interpolated_vars() {
+        assert_eq!(
+            interpolated_vars("${A} $B $$C ${D:-x} ${E-x} ${F:?err} ${G:+x} $${H}"),
+            ["A", "B", "F"]
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        let conf_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(conf_dir.path().join("services")).unwrap();
+        let files = BTreeMap::from([(
+            "a".to_string(),
+            parse(
+                r#"
+services:
+  a:
+    environment:
+      KEY: ${DEFINED}
+      OTHER: ${UNDEFINED}
+      SYNCED: ${OIDC_UNSYNCED}
+      UNKNOWN_OIDC: ${OIDC_UNKNOWN}
+      DEFAULT: ${UNSET:-foo}
+    volumes:
+      - data:/data
+      - undeclared:/other
+      - ../pki:/pki
+      - ../missing:/missing
+    configs:
+      - config.toml
+    secrets:
+      - source: key.pem
+        target: /key.pem
+    depends_on:
+      - b
+volumes:
+  data:
+configs:
+  config.toml:
+    content: foo
+"#,
+            ),
+        )]);
+        std::fs::create_dir(conf_dir.path().join("pki")).unwrap();
+        let problems = check_references(
+            &files,
+            "# comment\nDEFINED=\"x\"\n",
+            &BTreeSet::from(["OIDC_UNSYNCED".to_string()]),
+            conf_dir.path(),
+        );
+        assert_eq!(
+            problems,
+            [
+                "a references undefined variable ${OIDC_UNKNOWN}".to_string(),
+                "a references undefined variable ${UNDEFINED}".to_string(),
+                "a uses undeclared volume undeclared".to_string(),
+                "a uses undeclared secret key.pem".to_string(),
+                "a depends on unknown service b".to_string(),
+                format!(
+                    "a mounts {} which does not exist",
+                    conf_dir.path().join("services/../missing").display()
+                ),
+            ]
+        );
+    }
+}
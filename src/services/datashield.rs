@@ -1,7 +1,8 @@
-use std::{fs, marker::PhantomData, path::PathBuf};
+use std::{marker::PhantomData, path::PathBuf};
 
+use anyhow::Context;
 use askama::Template;
-use url::Url;
+use url::{Host, Url};
 
 use crate::{
     config::Config,
@@ -45,7 +46,7 @@ impl<T: BrokerProvider + OidcProvider> Service for DataShield<T> {
     fn from_config(
         conf: Self::ServiceConfig,
         (fw_proxy, pg, beam_proxy, beam_connect): super::Deps<Self>,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
         beam_connect.add_local_target(LocalTarget::new(
             format!("{}:443", conf.site_id),
             format!("{}-opal:8443", T::network_name()),
@@ -53,25 +54,30 @@ impl<T: BrokerProvider + OidcProvider> Service for DataShield<T> {
         ));
 
         // Self signed opal cert generation
+        let opal_host = format!("{}-opal", T::network_name());
         let key_path = conf
             .path
             .join(format!("pki/{}-opal.priv.pem", T::network_name()));
-        let cert_path = conf.trusted_ca_certs().join("opal-cert.pem");
-        if !(key_path.exists() && cert_path.exists()) {
-            let keypair =
-                rcgen::generate_simple_self_signed([format!("{}-opal", T::network_name())])
-                    .expect("Failed to generate opal cert");
-            fs::write(&key_path, keypair.signing_key.serialize_pem())
-                .expect("Failed to write opal priv key");
-            fs::write(&cert_path, keypair.cert.pem()).expect("Failed to write opal cert");
+        let cert_path = conf.trusted_ca_certs()?.join("opal-cert.pem");
+        // Lives in trusted-ca-certs so the renewed cert is picked up by the beam proxies
+        if crate::certs::ensure_self_signed(conf, &cert_path, &key_path, vec![opal_host.clone()])? {
+            conf.restart_required.set(true);
         }
+        crate::certs::check_cert_pair(
+            &cert_path,
+            &key_path,
+            &Host::Domain(opal_host),
+            conf.cert_expiry_warning_days,
+        )
+        .context("Invalid opal certificate")?;
 
         let tm_beam = beam_proxy.add_service("token-manager");
+        let oidc = OidcClient::<T>::add_private_redirect_path(conf, "/opal/*")?;
         let mut local_conf = conf.local_conf.borrow_mut();
-        Self {
+        Ok(Self {
             fw_proxy_url: fw_proxy.get_url(),
             tm_beam,
-            oidc: OidcClient::<T>::add_private_redirect_path(conf, "/opal/*"),
+            oidc,
             db: pg.connect_info(),
             exporter_password: None,
             tm_pw: local_conf.generate_secret::<10, Self>("token-manager"),
@@ -81,7 +87,7 @@ impl<T: BrokerProvider + OidcProvider> Service for DataShield<T> {
             deps: PhantomData,
             opal_key_path: key_path,
             opal_cert_path: cert_path,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -50,11 +50,11 @@ impl<T: BlazeProvider> Service for DirectorySync<T> {
         format!("{}-directory-sync", T::balze_service_name())
     }
 
-    fn from_config(conf: Self::ServiceConfig, (_blaze,): Deps<Self>) -> Self {
-        DirectorySync {
+    fn from_config(conf: Self::ServiceConfig, (_blaze,): Deps<Self>) -> anyhow::Result<Self> {
+        Ok(DirectorySync {
             blaze_url: Blaze::<T>::get_url(),
             conf: conf.clone(),
             blaze_provider: PhantomData,
-        }
+        })
     }
 }
@@ -21,6 +21,8 @@ pub struct DnpmNode {
     site_id: String,
     authup_secret: String,
     mysql_root_password: String,
+    /// Docker secret holding the mysql root password if secrets are passed as files
+    mysql_root_secret: Option<String>,
 }
 
 impl Service for DnpmNode {
@@ -31,16 +33,18 @@ impl Service for DnpmNode {
     fn from_config(
         (conf, global_conf): Self::ServiceConfig,
         (traefik,): super::Deps<Self>,
-    ) -> Self {
-        traefik.add_basic_auth_user("dnpm-etl".to_string());
+    ) -> anyhow::Result<Self> {
+        traefik.add_basic_auth_user("dnpm-etl".to_string())?;
         let mut local_conf = global_conf.local_conf.borrow_mut();
-        Self {
+        let mysql_root = local_conf.generate_secret_var::<10, Self>("mysql_root");
+        Ok(Self {
             conf,
             host: global_conf.hostname.to_string(),
             site_id: global_conf.site_id.to_string(),
             authup_secret: local_conf.generate_secret::<10, Self>("authup"),
-            mysql_root_password: local_conf.generate_secret::<10, Self>("mysql_root"),
-        }
+            mysql_root_password: format!("${{{mysql_root}}}"),
+            mysql_root_secret: global_conf.secret_files.then_some(mysql_root),
+        })
     }
 
     fn service_name() -> String {
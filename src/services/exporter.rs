@@ -32,7 +32,10 @@ impl<T: BrokerProvider + BlazeProvider + OidcProvider> Service for Exporter<T> {
 
     type ServiceConfig = &'static Config;
 
-    fn from_config(conf: Self::ServiceConfig, (focus, pg, ds): super::Deps<Self>) -> Self {
+    fn from_config(
+        conf: Self::ServiceConfig,
+        (focus, pg, ds): super::Deps<Self>,
+    ) -> anyhow::Result<Self> {
         let api_key = conf
             .local_conf
             .borrow_mut()
@@ -51,7 +54,7 @@ impl<T: BrokerProvider + BlazeProvider + OidcProvider> Service for Exporter<T> {
         } else {
             None
         };
-        Self {
+        Ok(Self {
             api_key,
             host: conf.hostname.to_string(),
             opal_password,
@@ -59,7 +62,7 @@ impl<T: BrokerProvider + BlazeProvider + OidcProvider> Service for Exporter<T> {
             blaze_host: <Blaze<T> as Service>::service_name(),
             project: T::network_name(),
             deps: PhantomData,
-        }
+        })
     }
 
     fn service_name() -> String {
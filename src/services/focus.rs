@@ -37,12 +37,12 @@ impl<T: BrokerProvider, B: BlazeProvider> Service for Focus<T, Blaze<B>> {
     type Dependencies = (BeamProxy<T>, Blaze<B>);
     type ServiceConfig = String;
 
-    fn from_config(tag: Self::ServiceConfig, (beam_proxy, _blaze): Deps<Self>) -> Self
-    where
-        Self: Sized,
-    {
+    fn from_config(
+        tag: Self::ServiceConfig,
+        (beam_proxy, _blaze): Deps<Self>,
+    ) -> anyhow::Result<Self> {
         let beam = beam_proxy.add_service("focus");
-        Focus {
+        Ok(Focus {
             beam,
             beam_and_blaze: PhantomData,
             endpoint_url: Some(Blaze::<B>::get_url()),
@@ -52,7 +52,7 @@ impl<T: BrokerProvider, B: BlazeProvider> Service for Focus<T, Blaze<B>> {
             provider_data: None,
             auth_header: None,
             postgres_connection_string: None,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -65,22 +65,19 @@ impl<T: BrokerProvider> Service for Focus<T, EucaimEndpointType> {
     type Dependencies = (BeamProxy<T>,);
     type ServiceConfig = EucaimConfig;
 
-    fn from_config(config: Self::ServiceConfig, (beam_proxy,): Deps<Self>) -> Self
-    where
-        Self: Sized,
-    {
+    fn from_config(config: Self::ServiceConfig, (beam_proxy,): Deps<Self>) -> anyhow::Result<Self> {
         let beam = beam_proxy.add_service("focus");
-        Focus {
+        Ok(Focus {
             beam,
             beam_and_blaze: PhantomData,
             endpoint_url: config.endpoint_url,
             tag: "develop".to_string(),
-            endpoint_type: serde_json::to_string(&config.endpoint_type).unwrap(),
+            endpoint_type: serde_json::to_string(&config.endpoint_type)?,
             exporter: None,
             provider_data: Some((config.provider, config.provider_icon)),
             auth_header: config.auth_header,
             postgres_connection_string: config.postgres_connection_string,
-        }
+        })
     }
 
     fn service_name() -> String {
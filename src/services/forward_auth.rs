@@ -0,0 +1,65 @@
+use std::marker::PhantomData;
+
+use askama::Template;
+use url::Url;
+
+use crate::config::Config;
+
+use super::{
+    BrokerProvider, ForwardProxy, OidcClient, OidcProvider, PrivateOidcClient, Service, Traefik,
+};
+
+/// oauth2-proxy used as a traefik forward auth middleware to protect paths with SSO login
+#[derive(Debug, Template)]
+#[template(path = "forward_auth.yml")]
+pub struct ForwardAuth<T: OidcProvider> {
+    provider: PhantomData<T>,
+    hostname: String,
+    oidc: PrivateOidcClient,
+    allowed_group: String,
+    cookie_secret: String,
+    fw_proxy_url: Url,
+    fw_proxy_name: String,
+}
+
+impl<T: OidcProvider> ForwardAuth<T> {
+    /// Name of the traefik middleware that requires a login
+    pub fn middleware_name() -> String {
+        Self::service_name()
+    }
+
+    fn path_prefix() -> String {
+        format!("/oauth2-{}", T::BeamProvider::network_name())
+    }
+}
+
+impl<T: OidcProvider> Service for ForwardAuth<T> {
+    type Dependencies = (Traefik, ForwardProxy);
+    type ServiceConfig = &'static Config;
+
+    fn from_config(
+        conf: Self::ServiceConfig,
+        (_traefik, fw_proxy): super::Deps<Self>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            provider: PhantomData,
+            hostname: conf.hostname.to_string(),
+            oidc: OidcClient::<T>::add_private_redirect_path(
+                conf,
+                &format!("{}/callback", Self::path_prefix()),
+            )?,
+            allowed_group: T::admin_group(conf),
+            // oauth2-proxy requires 16, 24 or 32 bytes
+            cookie_secret: conf
+                .local_conf
+                .borrow_mut()
+                .generate_secret::<32, Self>("cookie_secret"),
+            fw_proxy_url: fw_proxy.get_url(),
+            fw_proxy_name: ForwardProxy::service_name(),
+        })
+    }
+
+    fn service_name() -> String {
+        format!("{}-forward-auth", T::BeamProvider::network_name())
+    }
+}
@@ -23,11 +23,11 @@ impl Service for ForwardProxy {
     type Dependencies = ();
     type ServiceConfig = &'static Config;
 
-    fn from_config(conf: Self::ServiceConfig, _: super::Deps<Self>) -> Self {
-        Self {
+    fn from_config(conf: Self::ServiceConfig, _: super::Deps<Self>) -> anyhow::Result<Self> {
+        Ok(Self {
             https_proxy_url: conf.https_proxy_url.clone(),
-            trusted_ca_certs: conf.trusted_ca_certs(),
-        }
+            trusted_ca_certs: conf.trusted_ca_certs()?,
+        })
     }
 
     fn service_name() -> String {
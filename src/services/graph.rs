@@ -0,0 +1,323 @@
+use std::{
+    any::TypeId,
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::bridgehead::Bridgehead;
+
+use super::Service;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    Required,
+    /// Only used if the dependency gets installed by something else
+    Optional,
+}
+
+/// Dependency graph of all services registered in a `ServiceMap`
+pub struct ServiceGraph {
+    deps: solvent::DepGraph<TypeId>,
+    names: HashMap<TypeId, String>,
+    edges: HashMap<(TypeId, TypeId), DependencyKind>,
+    origins: HashMap<TypeId, Vec<Origin>>,
+}
+
+/// Module and config key that explicitly installed a service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Origin {
+    pub module: &'static str,
+    /// Set by the module through `PostInstallBuilder::config_key`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphNode<'a> {
+    name: &'a str,
+    installed: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    origins: &'a [Origin],
+}
+
+#[derive(Debug, Serialize)]
+struct GraphEdge<'a> {
+    from: &'a str,
+    to: &'a str,
+    kind: DependencyKind,
+}
+
+impl ServiceGraph {
+    pub const ROOT: TypeId = TypeId::of::<Bridgehead>();
+
+    pub fn new() -> Self {
+        let mut deps = solvent::DepGraph::new();
+        deps.register_node(Self::ROOT);
+        Self {
+            deps,
+            names: HashMap::from([(Self::ROOT, "bridgehead".to_string())]),
+            edges: HashMap::new(),
+            origins: HashMap::new(),
+        }
+    }
+
+    pub fn register_dependency<T: Service>(&mut self, parent: TypeId, kind: DependencyKind) {
+        let dep = TypeId::of::<T>();
+        self.deps.register_dependency(parent, dep);
+        self.names
+            .entry(dep)
+            .or_insert_with(<T as Service>::service_name);
+        self.edges
+            .entry((parent, dep))
+            .and_modify(|k| *k = (*k).min(kind))
+            .or_insert(kind);
+    }
+
+    pub fn add_origin<T: Service>(&mut self, module: &'static str) {
+        self.origins
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .push(Origin {
+                module,
+                config_key: None,
+            });
+    }
+
+    /// Records the config key of the latest origin of `T`
+    pub fn set_config_key<T: Service>(&mut self, key: String) {
+        if let Some(origin) = self
+            .origins
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|o| o.last_mut())
+        {
+            origin.config_key = Some(key);
+        }
+    }
+
+    /// All registered services ordered such that every service comes after its dependencies
+    pub fn install_order(&self) -> anyhow::Result<Vec<TypeId>> {
+        self.deps
+            .dependencies_of(&Self::ROOT)?
+            .map(|dep| {
+                dep.copied()
+                    .map_err(|_| anyhow::anyhow!("Services have cyclic dependencies"))
+            })
+            .collect()
+    }
+
+    pub fn name(&self, id: &TypeId) -> &str {
+        &self.names[id]
+    }
+
+    /// Services that will be created, i.e. everything reachable from the root through required dependencies
+    pub fn installed(&self) -> BTreeSet<TypeId> {
+        let mut installed = BTreeSet::new();
+        let mut todo = vec![Self::ROOT];
+        while let Some(node) = todo.pop() {
+            for (&(parent, dep), kind) in &self.edges {
+                if parent == node && *kind == DependencyKind::Required && installed.insert(dep) {
+                    todo.push(dep);
+                }
+            }
+        }
+        installed
+    }
+
+    /// Names of the installed services that directly or indirectly depend on the service `name` including itself
+    pub fn dependents(&self, name: &str) -> BTreeSet<String> {
+        let installed = self.installed();
+        let mut todo: Vec<_> = installed
+            .iter()
+            .filter(|id| self.names[id] == name)
+            .copied()
+            .collect();
+        let mut dependents: BTreeSet<_> = todo.iter().copied().collect();
+        while let Some(node) = todo.pop() {
+            for &(parent, dep) in self.edges.keys() {
+                if dep == node && installed.contains(&parent) && dependents.insert(parent) {
+                    todo.push(parent);
+                }
+            }
+        }
+        dependents.iter().map(|id| self.names[id].clone()).collect()
+    }
+
+    fn nodes_and_edges(&self) -> (Vec<GraphNode<'_>>, Vec<GraphEdge<'_>>) {
+        let installed = self.installed();
+        let mut nodes: Vec<_> = self
+            .names
+            .iter()
+            .map(|(id, name)| GraphNode {
+                name,
+                installed: *id == Self::ROOT || installed.contains(id),
+                origins: self.origins.get(id).map_or(&[], Vec::as_slice),
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.name);
+        let mut edges: Vec<_> = self
+            .edges
+            .iter()
+            .map(|((from, to), &kind)| GraphEdge {
+                from: self.name(from),
+                to: self.name(to),
+                kind,
+            })
+            .collect();
+        edges.sort_by_key(|e| (e.from, e.to));
+        (nodes, edges)
+    }
+
+    /// Graphviz representation. Services that are not installed and optional dependencies are dashed.
+    pub fn to_dot(&self) -> String {
+        let (nodes, edges) = self.nodes_and_edges();
+        let mut out = String::from("digraph services {\n");
+        for node in nodes {
+            let style = if node.installed { "solid" } else { "dashed" };
+            _ = writeln!(out, "    \"{}\" [style={style}];", node.name);
+        }
+        for edge in edges {
+            let style = match edge.kind {
+                DependencyKind::Required => "[label=\"required\"]",
+                DependencyKind::Optional => "[label=\"optional\", style=dashed]",
+            };
+            _ = writeln!(out, "    \"{}\" -> \"{}\" {style};", edge.from, edge.to);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Explains why a service is part of the bridgehead by tracing it back to the config keys that installed it
+    pub fn explain(&self, service: &str) -> anyhow::Result<String> {
+        let Some((&id, _)) = self.names.iter().find(|(_, name)| *name == service) else {
+            anyhow::bail!("Unknown service {service}");
+        };
+        let installed = self.installed();
+        if !installed.contains(&id) {
+            anyhow::bail!("{service} is not installed with the current config");
+        }
+        let mut out = String::new();
+        self.explain_node(id, &installed, 0, &mut out);
+        let mut optional_users: Vec<_> = self
+            .edges
+            .iter()
+            .filter(|&(&(parent, dep), &kind)| {
+                dep == id && kind == DependencyKind::Optional && installed.contains(&parent)
+            })
+            .map(|((parent, _), _)| self.name(parent))
+            .collect();
+        if !optional_users.is_empty() {
+            optional_users.sort();
+            _ = writeln!(out, "optionally used by {}", optional_users.join(", "));
+        }
+        Ok(out)
+    }
+
+    fn explain_node(
+        &self,
+        id: TypeId,
+        installed: &BTreeSet<TypeId>,
+        depth: usize,
+        out: &mut String,
+    ) {
+        let indent = "  ".repeat(depth);
+        if depth == 0 {
+            _ = writeln!(out, "{}", self.name(&id));
+        }
+        for origin in self.origins.get(&id).into_iter().flatten() {
+            _ = write!(out, "{indent}  installed by module {}", origin.module);
+            if let Some(key) = &origin.config_key {
+                _ = write!(out, " because of {key}");
+            }
+            _ = writeln!(out);
+        }
+        let mut parents: Vec<_> = self
+            .edges
+            .iter()
+            .filter(|&(&(parent, dep), &kind)| {
+                dep == id
+                    && parent != Self::ROOT
+                    && kind == DependencyKind::Required
+                    && installed.contains(&parent)
+            })
+            .map(|(&(parent, _), _)| parent)
+            .collect();
+        parents.sort_by_key(|p| self.name(p));
+        for parent in parents {
+            _ = writeln!(out, "{indent}  required by {}", self.name(&parent));
+            self.explain_node(parent, installed, depth + 1, out);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let (nodes, edges) = self.nodes_and_edges();
+        serde_json::to_string_pretty(&serde_json::json!({
+            "nodes": nodes,
+            "edges": edges,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{config::Config, modules, services::ServiceMap};
+
+    #[test]
+    fn test_optional_dependencies() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        let conf = Config::load(&temp_dir.path().to_path_buf()).unwrap();
+        let mut services = ServiceMap::new(Box::leak(Box::new(conf)));
+        modules::MODULES
+            .iter()
+            .try_for_each(|&m| services.install_module(m))
+            .unwrap();
+        let dot = services.graph().to_dot();
+        assert!(dot.contains("\"ccp-exporter\" -> \"ccp-focus\" [label=\"required\"];"));
+        assert!(dot.contains("\"ccp-exporter\" -> \"ccp-exporter-db\" [label=\"required\"];"));
+        assert!(dot.contains(
+            "\"ccp-exporter\" -> \"ccp-datashield\" [label=\"optional\", style=dashed];"
+        ));
+        assert!(dot.contains("\"ccp-datashield\" [style=dashed];"));
+        assert!(dot.contains("\"ccp-exporter-db\" [style=solid];"));
+    }
+
+    #[test]
+    fn test_explain() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n[ccp]\ndatashield = {}\n[dnpm]\nnode = { zpm_site = \"dummy\" }\n",
+        )
+        .unwrap();
+        let conf = Config::load(&temp_dir.path().to_path_buf()).unwrap();
+        let mut services = ServiceMap::new(Box::leak(Box::new(conf)));
+        modules::MODULES
+            .iter()
+            .try_for_each(|&m| services.install_module(m))
+            .unwrap();
+        let explanation = services.graph().explain("ccp-beam-connect").unwrap();
+        assert!(
+            explanation.contains("installed by module dnpm because of dnpm.node\n"),
+            "{explanation}"
+        );
+        assert!(
+            explanation.contains("required by ccp-datashield"),
+            "{explanation}"
+        );
+        assert!(
+            explanation.contains("installed by module ccp because of ccp.datashield"),
+            "{explanation}"
+        );
+        assert!(services.graph().explain("ccp-exporter").is_err());
+        assert!(services.graph().explain("nonexistent").is_err());
+    }
+}
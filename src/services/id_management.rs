@@ -49,14 +49,17 @@ impl Service for IdManagement<CcpDefault> {
     fn from_config(
         (idm_conf, conf): Self::ServiceConfig,
         (_traefik, fw_proxy, pg): super::Deps<Self>,
-    ) -> Self {
-        Self {
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             id: legacy_id_mapping(&conf.site_id),
             hostname: conf.hostname.to_string(),
             conf: idm_conf,
             fw_proxy_url: fw_proxy.get_url(),
             fw_proxy_name: fw_proxy.service_name(),
-            oidc: OidcClient::<CcpDefault>::add_private_redirect_path(conf, "/oauth2-idm/callback"),
+            oidc: OidcClient::<CcpDefault>::add_private_redirect_path(
+                conf,
+                "/oauth2-idm/callback",
+            )?,
             oidc_group: CcpDefault::admin_group(conf),
             project: PhantomData,
             db: pg.connect_info(),
@@ -64,7 +67,7 @@ impl Service for IdManagement<CcpDefault> {
                 .local_conf
                 .borrow_mut()
                 .generate_secret::<10, Self>("apikey"),
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -1,18 +1,25 @@
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs,
     marker::PhantomData,
 };
 
 use anyhow::Context;
 use askama::Template;
+use serde_yaml_ng::Mapping;
 
-use crate::{Config, bridgehead::Bridgehead, modules::Module};
+use crate::{Config, bridgehead::Bridgehead, config::ConfigError, modules::Module};
 
 pub mod beam_connect;
+pub mod compose;
+use compose::ComposeFile;
 pub mod dnpm_node;
+mod graph;
+pub use graph::{DependencyKind, ServiceGraph};
 pub mod obds2fhir;
+mod overrides;
+pub use overrides::ServiceOverride;
 pub mod podest2fhir;
 pub use beam_connect::BeamConnect;
 mod datashield;
@@ -28,6 +35,8 @@ mod id_management;
 pub use id_management::*;
 mod directory_sync;
 pub use directory_sync::*;
+mod forward_auth;
+pub use forward_auth::ForwardAuth;
 mod forward_proxy;
 pub use forward_proxy::ForwardProxy;
 mod secret_sync;
@@ -48,7 +57,9 @@ pub trait Service: ToCompose + 'static {
     type Dependencies: ServiceTuple;
     type ServiceConfig;
 
-    fn from_config(conf: Self::ServiceConfig, deps: Deps<Self>) -> Self;
+    fn from_config(conf: Self::ServiceConfig, deps: Deps<Self>) -> anyhow::Result<Self>
+    where
+        Self: Sized;
 
     fn service_name() -> String;
 }
@@ -56,9 +67,11 @@ pub trait Service: ToCompose + 'static {
 pub trait ServiceTuple {
     type DepRefs<'t>;
 
-    fn get_or_create<'services>(services: &'services mut ServiceMap) -> Self::DepRefs<'services>;
+    fn get_or_create<'services>(
+        services: &'services mut ServiceMap,
+    ) -> anyhow::Result<Self::DepRefs<'services>>;
 
-    fn register_deps(parent: TypeId, deps: &mut solvent::DepGraph<TypeId>);
+    fn register_deps(parent: TypeId, graph: &mut ServiceGraph);
 }
 
 macro_rules! service_tuple_option {
@@ -67,11 +80,11 @@ macro_rules! service_tuple_option {
         impl<$($ts: Service,)* $($opt_ts: Service,)*> ServiceTuple for ($($ts,)* $(Option<$opt_ts>,)*) {
             type DepRefs<'t> = ($(&'t mut $ts,)* $(Option<&'t mut $opt_ts>,)*);
 
-            fn get_or_create<'services>(services: &'services mut ServiceMap) -> Self::DepRefs<'services> {
+            fn get_or_create<'services>(services: &'services mut ServiceMap) -> anyhow::Result<Self::DepRefs<'services>> {
                 // Ensure all required services are created
                 $(
                     if !services.contains::<$ts>() {
-                        let service = $ts::from_default_config(services);
+                        let service = $ts::from_default_config(services)?;
                         services.insert(service);
                     }
                 )*
@@ -79,21 +92,22 @@ macro_rules! service_tuple_option {
                     $(&TypeId::of::<$ts>(),)*
                     $(&TypeId::of::<$opt_ts>(),)*
                 ]);
-                (
+                Ok((
                     // All required services are guaranteed to be created at this point
                     $((($ts.unwrap().as_mut() as &mut dyn Any).downcast_mut::<$ts>().unwrap()),)*
                     // Optional services may be absent
                     $($opt_ts.map(|s| (s.as_mut() as &mut dyn Any).downcast_mut::<$opt_ts>().unwrap()),)*
-                )
+                ))
             }
 
-            fn register_deps(parent: TypeId, deps: &mut solvent::DepGraph<TypeId>) {
-                deps.register_dependencies(parent, vec![$(TypeId::of::<$ts>(),)* $(TypeId::of::<$opt_ts>(),)*]);
+            fn register_deps(parent: TypeId, graph: &mut ServiceGraph) {
                 $(
-                    $ts::Dependencies::register_deps(TypeId::of::<$ts>(), deps);
+                    graph.register_dependency::<$ts>(parent, DependencyKind::Required);
+                    $ts::Dependencies::register_deps(TypeId::of::<$ts>(), graph);
                 )*
                 $(
-                    $opt_ts::Dependencies::register_deps(TypeId::of::<$opt_ts>(), deps);
+                    graph.register_dependency::<$opt_ts>(parent, DependencyKind::Optional);
+                    $opt_ts::Dependencies::register_deps(TypeId::of::<$opt_ts>(), graph);
                 )*
             }
         }
@@ -122,11 +136,12 @@ macro_rules! service_tuple {
         impl<$($ts: Service,)*> ServiceTuple for ($($ts,)*) {
             type DepRefs<'t> = ($(&'t mut $ts,)*);
 
-            fn get_or_create<'services>(services: &'services mut ServiceMap) -> Self::DepRefs<'services> {
+            #[allow(clippy::unused_unit)]
+            fn get_or_create<'services>(services: &'services mut ServiceMap) -> anyhow::Result<Self::DepRefs<'services>> {
                 // Ensure all services are created
                 $(
                     if !services.contains::<$ts>() {
-                        let service = $ts::from_default_config(services);
+                        let service = $ts::from_default_config(services)?;
                         services.insert(service);
                     }
                 )*
@@ -134,13 +149,13 @@ macro_rules! service_tuple {
                     $(&TypeId::of::<$ts>(),)*
                 ]);
                 // All services are guaranteed to be created at this point
-                ($(($ts.unwrap().as_mut() as &mut dyn Any).downcast_mut::<$ts>().unwrap(),)*)
+                Ok(($(($ts.unwrap().as_mut() as &mut dyn Any).downcast_mut::<$ts>().unwrap(),)*))
             }
 
-            fn register_deps(parent: TypeId, deps: &mut solvent::DepGraph<TypeId>) {
-                deps.register_dependencies(parent, vec![$(TypeId::of::<$ts>(),)*]);
+            fn register_deps(parent: TypeId, graph: &mut ServiceGraph) {
                 $(
-                    $ts::Dependencies::register_deps(TypeId::of::<$ts>(), deps);
+                    graph.register_dependency::<$ts>(parent, DependencyKind::Required);
+                    $ts::Dependencies::register_deps(TypeId::of::<$ts>(), graph);
                 )*
             }
         }
@@ -150,7 +165,9 @@ macro_rules! service_tuple {
 }
 
 pub trait DefaultService: Service {
-    fn from_default_config(service_map: &mut ServiceMap) -> Self;
+    fn from_default_config(service_map: &mut ServiceMap) -> anyhow::Result<Self>
+    where
+        Self: Sized;
 }
 
 impl<T> DefaultService for T
@@ -158,21 +175,27 @@ where
     T: Service,
     T::ServiceConfig: 'static,
 {
-    fn from_default_config(service_map: &mut ServiceMap) -> Self {
+    fn from_default_config(service_map: &mut ServiceMap) -> anyhow::Result<Self> {
         let conf: T::ServiceConfig =
             if TypeId::of::<T::ServiceConfig>() == TypeId::of::<&'static Config>() {
                 unsafe { std::mem::transmute_copy::<&Config, _>(&service_map.config) }
             } else if TypeId::of::<T::ServiceConfig>() == TypeId::of::<()>() {
                 unsafe { std::mem::transmute_copy(&()) }
             } else {
-                panic!(
+                anyhow::bail!(
                     "Cannot create {} because it needs to be constructed explicitly with {}",
-                    std::any::type_name::<T>(),
+                    <T as Service>::service_name(),
                     std::any::type_name::<T::ServiceConfig>()
                 );
             };
-        let deps = T::Dependencies::get_or_create(service_map);
+        let deps = T::Dependencies::get_or_create(service_map).with_context(|| {
+            format!(
+                "Failed to create dependencies of {}",
+                <T as Service>::service_name()
+            )
+        })?;
         T::from_config(conf, deps)
+            .with_context(|| format!("Failed to create {}", <T as Service>::service_name()))
     }
 }
 
@@ -183,16 +206,22 @@ service_tuple!(T1, T2, T3);
 service_tuple!(T1, T2, T3, T4);
 
 pub trait ToCompose: Any {
-    fn render(&self, config: &'static Config) -> anyhow::Result<String>;
+    fn compose(&self, config: &'static Config) -> anyhow::Result<ComposeFile>;
 
     fn service_name(&self) -> String;
 }
 
 impl<T: Template + Service> ToCompose for T {
-    fn render(&self, config: &'static Config) -> anyhow::Result<String> {
+    fn compose(&self, config: &'static Config) -> anyhow::Result<ComposeFile> {
         let values = [("config", config as &dyn Any)];
-        Template::render_with_values(self, &values as &dyn askama::Values)
-            .with_context(|| format!("Failed to render {}", std::any::type_name::<T>()))
+        let rendered = Template::render_with_values(self, &values as &dyn askama::Values)
+            .with_context(|| format!("Failed to render {}", std::any::type_name::<T>()))?;
+        serde_yaml_ng::from_str(&rendered).with_context(|| {
+            format!(
+                "Template of {} is not a valid compose file",
+                std::any::type_name::<T>()
+            )
+        })
     }
 
     fn service_name(&self) -> String {
@@ -200,12 +229,17 @@ impl<T: Template + Service> ToCompose for T {
     }
 }
 
+type Constructor = Box<dyn FnOnce(&mut ServiceMap) -> anyhow::Result<Box<dyn ToCompose>>>;
+type PostInstall = Box<dyn FnOnce(&mut dyn ToCompose)>;
+
 pub struct ServiceMap {
-    deps: solvent::DepGraph<TypeId>,
-    constructors: HashMap<TypeId, Box<dyn FnOnce(&mut Self) -> Box<dyn ToCompose>>>,
-    post_install: HashMap<TypeId, Vec<Box<dyn FnOnce(&mut dyn ToCompose)>>>,
+    graph: ServiceGraph,
+    constructors: HashMap<TypeId, Constructor>,
+    post_install: HashMap<TypeId, Vec<PostInstall>>,
     map: HashMap<TypeId, Box<dyn ToCompose>>,
     config: &'static Config,
+    /// Module that is currently installing services
+    current_module: Option<&'static str>,
 }
 
 pub struct PostInstallBuilder<'a, T>(&'a mut ServiceMap, PhantomData<T>);
@@ -221,6 +255,12 @@ impl<T: Service> PostInstallBuilder<'_, T> {
             }));
         self
     }
+
+    /// Records the config key that caused this installation
+    pub fn config_key(self, key: impl Into<String>) -> Self {
+        self.0.graph.set_config_key::<T>(key.into());
+        self
+    }
 }
 
 impl std::fmt::Debug for ServiceMap {
@@ -230,17 +270,14 @@ impl std::fmt::Debug for ServiceMap {
 }
 
 impl ServiceMap {
-    const ROOT_NODE: TypeId = TypeId::of::<Bridgehead>();
-
     pub fn new(config: &'static Config) -> Self {
-        let mut deps = solvent::DepGraph::new();
-        deps.register_node(Self::ROOT_NODE);
         Self {
-            deps,
+            graph: ServiceGraph::new(),
             constructors: HashMap::new(),
             post_install: HashMap::new(),
             map: HashMap::new(),
             config,
+            current_module: None,
         }
     }
 
@@ -250,18 +287,27 @@ impl ServiceMap {
     }
 
     pub fn write_all(&mut self) -> anyhow::Result<()> {
-        self.materialize();
-        self.write_composables()
+        self.write_generated_files()?;
+        #[cfg(not(test))]
+        self.generate_lockfile_and_pull()
+            .context("Failed to generate lockfile and pull images")?;
+        Ok(())
+    }
+
+    /// Writes all generated files without creating the lockfile or pulling any images
+    pub fn write_generated_files(&mut self) -> anyhow::Result<()> {
+        self.materialize()?;
+        let files = self.compose_files()?;
+        self.write_composables(&files)
             .context("Failed to write services")?;
-        Bridgehead::new(self.config).write()?;
+        let beam_networks = BEAM_NETWORKS.with_borrow(|nets| nets.keys().cloned().collect());
+        crate::enroll::adopt_legacy_enrollments(self.config, &beam_networks);
+        Bridgehead::for_services(self.config, &self.graph, &files).write()?;
         self.config.write_local_conf()?;
         fs::write(
             self.config.path.join(".gitignore"),
             include_str!("../../static/.gitignore"),
         )?;
-        #[cfg(not(test))]
-        self.generate_lockfile_and_pull()
-            .context("Failed to generate lockfile and pull images")?;
         Ok(())
     }
 
@@ -302,6 +348,8 @@ impl ServiceMap {
             self.config.path.join("docker-image.lock.yml"),
             output.stdout,
         )?;
+        // Keeps stdout free for the update report
+        pull_cmd.stdout(std::io::stderr());
         if !pull_cmd.status()?.success() {
             anyhow::bail!(
                 "Failed to pull images: {}",
@@ -311,6 +359,14 @@ impl ServiceMap {
         Ok(())
     }
 
+    pub fn config(&self) -> &'static Config {
+        self.config
+    }
+
+    pub fn graph(&self) -> &ServiceGraph {
+        &self.graph
+    }
+
     pub fn contains<T: ToCompose + Any>(&self) -> bool {
         self.map.contains_key(&TypeId::of::<T>())
     }
@@ -319,68 +375,163 @@ impl ServiceMap {
         &mut self,
         conf: T::ServiceConfig,
     ) -> PostInstallBuilder<'_, T> {
-        self.deps
-            .register_dependency(Self::ROOT_NODE, TypeId::of::<T>());
-        T::Dependencies::register_deps(TypeId::of::<T>(), &mut self.deps);
+        self.register_root::<T>();
         self.constructors.insert(
             TypeId::of::<T>(),
-            Box::new(|s| Box::new(T::from_config(conf, T::Dependencies::get_or_create(s)))),
+            Box::new(|s| {
+                let deps = T::Dependencies::get_or_create(s).with_context(|| {
+                    format!(
+                        "Failed to create dependencies of {}",
+                        <T as Service>::service_name()
+                    )
+                })?;
+                let service = T::from_config(conf, deps).with_context(|| {
+                    format!("Failed to create {}", <T as Service>::service_name())
+                })?;
+                Ok(Box::new(service))
+            }),
         );
         PostInstallBuilder(self, PhantomData)
     }
 
     pub fn install_default<T: DefaultService>(&mut self) -> PostInstallBuilder<'_, T> {
-        self.deps
-            .register_dependency(Self::ROOT_NODE, TypeId::of::<T>());
-        T::Dependencies::register_deps(TypeId::of::<T>(), &mut self.deps);
+        self.register_root::<T>();
         self.constructors.insert(
             TypeId::of::<T>(),
-            Box::new(|s| Box::new(T::from_default_config(s))),
+            Box::new(|s| Ok(Box::new(T::from_default_config(s)?))),
         );
         PostInstallBuilder(self, PhantomData)
     }
 
+    fn register_root<T: Service>(&mut self) {
+        self.graph
+            .register_dependency::<T>(ServiceGraph::ROOT, DependencyKind::Required);
+        T::Dependencies::register_deps(TypeId::of::<T>(), &mut self.graph);
+        if let Some(module) = self.current_module {
+            self.graph.add_origin::<T>(module);
+        }
+    }
+
     fn insert<T: Service>(&mut self, s: T) {
         self.map.insert(TypeId::of::<T>(), Box::new(s));
     }
 
-    pub fn install_module<M: Module>(&mut self, m: M) {
-        m.install(self, &self.config);
+    pub fn install_module<M: Module>(&mut self, m: M) -> anyhow::Result<()> {
+        self.current_module = Some(m.name());
+        let res = m.install(self, self.config);
+        self.current_module = None;
+        res
     }
 
-    fn write_composables(&self) -> anyhow::Result<()> {
+    fn write_composables(&self, files: &BTreeMap<String, ComposeFile>) -> anyhow::Result<()> {
         let services_dir = self.config.path.join("services");
         _ = fs::remove_dir_all(&services_dir);
         fs::create_dir_all(&services_dir)?;
-        for service in self.map.values() {
-            let service_name = service.service_name();
+        for (service_name, compose) in files {
             eprintln!("Generating service {service_name}");
             fs::write(
-                services_dir.join(format!("{}.yml", service.service_name())),
-                service.render(self.config)?,
+                services_dir.join(format!("{service_name}.yml")),
+                serde_yaml_ng::to_string(&compose)?,
             )?;
         }
         Ok(())
     }
 
-    fn materialize(&mut self) {
-        let deps = std::mem::take(&mut self.deps);
-        for dep in deps.dependencies_of(&Self::ROOT_NODE).unwrap() {
-            let dep = dep.expect("No cycle");
-            if self.map.contains_key(dep) {
+    /// Compose files of all created services keyed by service name with overrides applied and validated
+    fn compose_files(&self) -> anyhow::Result<BTreeMap<String, ComposeFile>> {
+        let mut files = BTreeMap::new();
+        for service in self.map.values() {
+            let name = service.service_name();
+            // e.g. a configured broker reusing the network name of an enabled module
+            anyhow::ensure!(
+                !files.contains_key(&name),
+                "Multiple services generate the compose service {name:?}"
+            );
+            files.insert(name, service.compose(self.config)?);
+        }
+        traefik::apply_acme(&mut files, self.config);
+        traefik::apply_routes(&mut files, self.config)?;
+        let overrides = &self.config.overrides;
+        let applied = overrides::apply_overrides(&mut files, overrides)?;
+        if let Some(unknown) = overrides.keys().find(|k| !applied.contains(k.as_str())) {
+            return Err(ConfigError::new(
+                format!("overrides.{unknown}"),
+                "no generated compose service has this name",
+            )
+            .into());
+        }
+        if self.config.secret_files {
+            self.declare_secret_files(&mut files);
+        }
+        let mut problems = compose::check_conflicts(&files);
+        let env = self.config.local_conf.borrow().to_env()?;
+        problems.extend(compose::check_references(
+            &files,
+            &env,
+            &secret_sync::synced_env_vars(),
+            &self.config.path,
+        ));
+        if !problems.is_empty() {
+            return Err(compose::InvalidCompose(problems).into());
+        }
+        Ok(files)
+    }
+
+    /// Declares the generated secrets used as docker secrets and drops the ones no longer interpolated from `.env`
+    fn declare_secret_files(&self, files: &mut BTreeMap<String, ComposeFile>) {
+        let mut local_conf = self.config.local_conf.borrow_mut();
+        let referenced: BTreeSet<_> = files
+            .values()
+            .flat_map(|f| f.services.values())
+            .flat_map(|s| s.referenced_vars())
+            .collect();
+        for (var, secret) in &mut local_conf.generated_secrets {
+            secret.env = referenced.contains(var);
+            secret.file = false;
+        }
+        for file in files.values_mut() {
+            let used: BTreeSet<_> = file
+                .services
+                .values()
+                .flat_map(|s| s.sources("secrets"))
+                .map(str::to_string)
+                .collect();
+            for var in used {
+                let Some(secret) = local_conf.generated_secrets.get_mut(&var) else {
+                    continue;
+                };
+                secret.file = true;
+                // Relative to the services directory like every other path in the compose files
+                let declaration =
+                    Mapping::from_iter([("file".into(), format!("../secrets/{var}").into())]);
+                file.secrets.entry(var).or_insert(Some(declaration));
+            }
+        }
+    }
+
+    /// Creates all services and returns their validated compose files without writing anything
+    pub fn compose(&mut self) -> anyhow::Result<BTreeMap<String, ComposeFile>> {
+        self.materialize()?;
+        self.compose_files()
+    }
+
+    fn materialize(&mut self) -> anyhow::Result<()> {
+        for dep in self.graph.install_order()? {
+            if self.map.contains_key(&dep) {
                 continue;
             }
-            let Some(c) = self.constructors.remove(dep) else {
+            let Some(c) = self.constructors.remove(&dep) else {
                 // We assume that this would be an optional dependency in this case
                 continue;
             };
-            let mut service = c(self);
-            if let Some(post_install) = self.post_install.remove(dep) {
+            let mut service = c(self)?;
+            if let Some(post_install) = self.post_install.remove(&dep) {
                 for post in post_install {
                     post(service.as_mut());
                 }
             }
-            self.map.insert(dep.clone(), service);
+            self.map.insert(dep, service);
         }
+        Ok(())
     }
 }
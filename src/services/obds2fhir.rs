@@ -6,7 +6,10 @@ use url::Url;
 
 use crate::{
     config::Config,
-    services::{Blaze, BlazeProvider, BrokerProvider, IdManagement, Service, Traefik},
+    services::{
+        AuthMethod, Blaze, BlazeProvider, BrokerProvider, IdManagement, OidcProvider, Service,
+        Traefik, TraefikAuth,
+    },
 };
 
 #[derive(Debug, Deserialize, Clone)]
@@ -19,6 +22,9 @@ pub struct Obds2FhirConfig {
     pub id_type: Option<String>,
     #[serde(default)]
     pub keep_internal_id: bool,
+    /// How the rest api is protected
+    #[serde(default)]
+    pub auth: AuthMethod,
 }
 
 #[derive(Debug, Template)]
@@ -35,12 +41,12 @@ where
     id_type: String,
     keep_internal_id: bool,
     salt: String,
-    middleware_name: String,
+    auth: TraefikAuth,
     prefix: String,
     kind: PhantomData<T>,
 }
 
-impl<T: BrokerProvider + BlazeProvider> Service for Obds2Fhir<IdManagement<T>>
+impl<T: BrokerProvider + BlazeProvider + OidcProvider> Service for Obds2Fhir<IdManagement<T>>
 where
     IdManagement<T>: Service,
 {
@@ -51,16 +57,19 @@ where
     fn from_config(
         (obds_conf, conf): Self::ServiceConfig,
         (traefik, ml): super::Deps<Self>,
-    ) -> Self {
-        let middleware_name = format!("{}-obds2fhir-auth", T::network_name());
-        traefik.add_basic_auth_user(middleware_name.clone());
+    ) -> anyhow::Result<Self> {
+        let auth = TraefikAuth::new::<T>(
+            obds_conf.auth,
+            format!("{}-obds2fhir-auth", T::network_name()),
+        );
+        traefik.add_auth(&auth)?;
         let salt = conf
             .local_conf
             .borrow_mut()
             .generate_secret::<30, Self>("salt");
-        Self {
+        Ok(Self {
             salt,
-            middleware_name,
+            auth,
             fhir_server_url: obds_conf
                 .fhir_server_url
                 .unwrap_or_else(|| Blaze::<T>::get_url().join("fhir").unwrap()),
@@ -83,7 +92,7 @@ where
             keep_internal_id: obds_conf.keep_internal_id,
             prefix: T::network_name().to_string(),
             kind: PhantomData,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+use serde_yaml_ng::{Mapping, Value};
+
+use crate::config::ConfigError;
+
+use super::compose::{ComposeFile, ComposeService};
+
+/// Tweaks to a generated compose service configured under `[overrides.<service-name>]`
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceOverride {
+    /// Replaces the whole image reference
+    pub image: Option<String>,
+    /// Replaces only the tag of the generated image
+    pub tag: Option<String>,
+    /// Added to the environment replacing variables with the same name
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+    pub resources: Option<Resources>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Additional volumes in compose short syntax e.g. `/srv/data:/data:ro`
+    #[serde(default)]
+    pub volumes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Resources {
+    pub cpus: Option<f64>,
+    /// Memory limit like `2g` or `512m`
+    pub memory: Option<String>,
+}
+
+/// Applies the overrides to the compose services of all files and returns the names of the overridden services
+pub fn apply_overrides<'a>(
+    files: &mut BTreeMap<String, ComposeFile>,
+    overrides: &'a BTreeMap<String, ServiceOverride>,
+) -> anyhow::Result<BTreeSet<&'a str>> {
+    let mut applied = BTreeSet::new();
+    for (name, service_override) in overrides {
+        let Some(service) = files.values_mut().find_map(|f| f.services.get_mut(name)) else {
+            continue;
+        };
+        service_override.apply(name, service)?;
+        applied.insert(name.as_str());
+    }
+    Ok(applied)
+}
+
+impl ServiceOverride {
+    fn apply(&self, name: &str, service: &mut ComposeService) -> anyhow::Result<()> {
+        let key = format!("overrides.{name}");
+        match (&self.image, &self.tag) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    ConfigError::new(key, "only one of image and tag can be overridden").into(),
+                );
+            }
+            (Some(image), None) => service.image = Some(image.clone()),
+            (None, Some(tag)) => {
+                let Some(image) = &service.image else {
+                    return Err(ConfigError::new(
+                        key,
+                        "can not override the tag of a service without an image",
+                    )
+                    .into());
+                };
+                service.image = Some(format!("{}:{tag}", strip_tag(image)));
+            }
+            (None, None) => {}
+        }
+        for (key, value) in &self.environment {
+            service.environment.insert(key, value);
+        }
+        for (key, value) in &self.labels {
+            service.labels.insert(key, value);
+        }
+        service
+            .volumes
+            .extend(self.volumes.iter().map(|v| v.as_str().into()));
+        if let Some(resources) = &self.resources {
+            let mut limits = Mapping::new();
+            if let Some(cpus) = resources.cpus {
+                limits.insert("cpus".into(), cpus.into());
+            }
+            if let Some(memory) = &resources.memory {
+                limits.insert("memory".into(), memory.as_str().into());
+            }
+            let deploy = service
+                .other
+                .entry("deploy".into())
+                .or_insert_with(|| Value::Mapping(Mapping::new()));
+            let Some(deploy) = deploy.as_mapping_mut() else {
+                anyhow::bail!("Failed to apply overrides to {name}: deploy is not a mapping");
+            };
+            deploy.insert(
+                "resources".into(),
+                Value::Mapping(Mapping::from_iter([("limits".into(), limits.into())])),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn strip_tag(image: &str) -> &str {
+    let image = image.split_once('@').map_or(image, |(name, _digest)| name);
+    let name_start = image.rfind('/').map_or(0, |i| i + 1);
+    match image[name_start..].rfind(':') {
+        Some(i) => &image[..name_start + i],
+        None => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENDERED: &str = r#"
+services:
+  foo:
+    image: docker.verbis.dkfz.de/cache/samply/foo:latest
+    environment:
+      - A=1
+      - B=2
+    labels:
+      - "traefik.enable=true"
+  bar:
+    image: bar:1.0
+"#;
+
+    fn files() -> BTreeMap<String, ComposeFile> {
+        BTreeMap::from([(
+            "foo".to_string(),
+            serde_yaml_ng::from_str(RENDERED).unwrap(),
+        )])
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let overrides: BTreeMap<String, ServiceOverride> = toml::from_str(
+            r#"
+            [foo]
+            tag = "1.2.3"
+            environment = { B = "3", C = "4" }
+            labels = { "traefik.enable" = "false" }
+            volumes = ["/srv/foo:/data"]
+            resources = { cpus = 0.5, memory = "1g" }
+            "#,
+        )
+        .unwrap();
+        let mut files = files();
+        let applied = apply_overrides(&mut files, &overrides).unwrap();
+        assert_eq!(applied, BTreeSet::from(["foo"]));
+        let foo = &files["foo"].services["foo"];
+        assert_eq!(
+            foo.image.as_deref(),
+            Some("docker.verbis.dkfz.de/cache/samply/foo:1.2.3")
+        );
+        let env: Vec<_> = foo
+            .environment
+            .keys()
+            .map(|k| (k, foo.environment.0[k].as_deref().unwrap()))
+            .collect();
+        assert_eq!(env, [("A", "1"), ("B", "3"), ("C", "4")]);
+        assert_eq!(foo.labels.0["traefik.enable"].as_deref(), Some("false"));
+        assert_eq!(foo.volumes[0], "/srv/foo:/data");
+        assert_eq!(foo.other["deploy"]["resources"]["limits"]["memory"], "1g");
+        assert_eq!(
+            files["foo"].services["bar"].image.as_deref(),
+            Some("bar:1.0")
+        );
+    }
+
+    #[test]
+    fn test_invalid_image_override() {
+        let overrides: BTreeMap<String, ServiceOverride> =
+            toml::from_str("[foo]\nimage = \"foo:1\"\ntag = \"2\"\n").unwrap();
+        let err = apply_overrides(&mut files(), &overrides).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>().unwrap().key,
+            "overrides.foo"
+        );
+    }
+
+    #[test]
+    fn test_unknown_service() {
+        let overrides = BTreeMap::from([("baz".to_string(), ServiceOverride::default())]);
+        assert!(
+            apply_overrides(&mut files(), &overrides)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_strip_tag() {
+        assert_eq!(strip_tag("localhost:5000/foo:1.0"), "localhost:5000/foo");
+        assert_eq!(strip_tag("localhost:5000/foo"), "localhost:5000/foo");
+        assert_eq!(strip_tag("foo@sha256:abc"), "foo");
+    }
+}
@@ -49,8 +49,8 @@ where
     fn from_config(
         (conf, profile): Self::ServiceConfig,
         (_blaze, _focus): super::Deps<Self>,
-    ) -> Self {
-        Self {
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             fhir_base_url: conf
                 .fhir_base_url
                 .unwrap_or_else(|| Blaze::<T>::get_url().join("/fhir").unwrap()),
@@ -61,7 +61,7 @@ where
             tag: conf.tag,
             profile,
             kind: PhantomData,
-        }
+        })
     }
 
     fn service_name() -> String {
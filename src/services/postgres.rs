@@ -15,6 +15,8 @@ where
     r#for: PhantomData<T>,
     user: String,
     password: String,
+    /// Docker secret holding the password if secrets are passed as files
+    password_secret: Option<String>,
     realm: String,
 }
 
@@ -44,16 +46,18 @@ impl<T: Service> Service for Postgres<T> {
     type Dependencies = ();
     type ServiceConfig = &'static crate::Config;
 
-    fn from_config(conf: Self::ServiceConfig, _deps: super::Deps<Self>) -> Self {
-        Self {
+    fn from_config(conf: Self::ServiceConfig, _deps: super::Deps<Self>) -> anyhow::Result<Self> {
+        let password = conf
+            .local_conf
+            .borrow_mut()
+            .generate_secret_var::<10, Self>("password");
+        Ok(Self {
             r#for: PhantomData,
             user: <T as Service>::service_name(),
             realm: <T as Service>::service_name(),
-            password: conf
-                .local_conf
-                .borrow_mut()
-                .generate_secret::<10, Self>("password"),
-        }
+            password: format!("${{{password}}}"),
+            password_secret: conf.secret_files.then_some(password),
+        })
     }
 
     fn service_name() -> String {
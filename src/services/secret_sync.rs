@@ -1,7 +1,7 @@
 use std::{
     any::{Any, TypeId},
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, hash_map::Entry},
     fs,
     process::Command,
 };
@@ -30,61 +30,79 @@ thread_local! {
 trait SyncOidc: Any {
     fn sync(&mut self) -> anyhow::Result<()>;
 
+    /// Variables this client adds to `.env` once synced
+    fn env_vars(&self) -> Vec<String>;
+
     fn get_local_conf(&self) -> &'static RefCell<LocalConf>;
 }
 
 impl<T: OidcProvider> OidcClient<T> {
-    fn new(conf: &'static Config) -> Self {
-        let mut dummy_fw_proxy = ForwardProxy::from_config(conf, ());
-        let beam_proxy = BeamProxy::from_config(conf, (&mut dummy_fw_proxy,));
+    fn new(conf: &'static Config) -> anyhow::Result<Self> {
+        let mut dummy_fw_proxy = ForwardProxy::from_config(conf, ())?;
+        let beam_proxy = BeamProxy::from_config(conf, (&mut dummy_fw_proxy,))?;
         let proxy_url = dummy_fw_proxy.https_proxy_url;
-        Self {
+        Ok(Self {
             beam_proxy,
             pub_redirect_paths: Default::default(),
             priv_redirect_urls: Default::default(),
             http_proxy_url: proxy_url,
             local_conf: &conf.local_conf,
-            synced: false,
-        }
+            // Generating into a scratch directory must not register clients so the cached ones get used
+            synced: conf.scratch,
+        })
+    }
+
+    fn public_client_name() -> String {
+        format!("{}_public_client", T::BeamProvider::network_name())
+    }
+
+    fn private_client_name() -> String {
+        format!("{}_client_secret", T::BeamProvider::network_name())
     }
 
-    pub fn add_public_redirect_path(conf: &'static Config, path: &str) -> PublicOidcClient {
+    fn with_client<R>(conf: &'static Config, f: impl FnOnce(&mut Self) -> R) -> anyhow::Result<R> {
         OIDC_CLIENTS.with_borrow_mut(|m| {
-            let syncer = m
-                .entry(TypeId::of::<T>())
-                .or_insert_with(|| Box::new(Self::new(conf)))
-                .as_mut() as &mut dyn Any;
-            syncer
+            let syncer = match m.entry(TypeId::of::<T>()) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(Box::new(Self::new(conf)?)),
+            };
+            Ok(f((syncer.as_mut() as &mut dyn Any)
                 .downcast_mut::<Self>()
-                .unwrap()
+                .unwrap()))
+        })
+    }
+
+    pub fn add_public_redirect_path(
+        conf: &'static Config,
+        path: &str,
+    ) -> anyhow::Result<PublicOidcClient> {
+        Self::with_client(conf, |client| {
+            client
                 .pub_redirect_paths
-                .extend(redirect_urls_for_path(path, &conf.hostname));
-        });
-        PublicOidcClient {
+                .extend(redirect_urls_for_path(path, &conf.hostname))
+        })?;
+        Ok(PublicOidcClient {
             provider: TypeId::of::<T>(),
             client_id: format!("{}-public", conf.site_id),
             get_issuer_url: T::issuer_url,
-        }
+        })
     }
 
-    pub fn add_private_redirect_path(conf: &'static Config, path: &str) -> PrivateOidcClient {
-        OIDC_CLIENTS.with_borrow_mut(|m| {
-            let syncer = m
-                .entry(TypeId::of::<T>())
-                .or_insert_with(|| Box::new(Self::new(conf)))
-                .as_mut() as &mut dyn Any;
-            syncer
-                .downcast_mut::<Self>()
-                .unwrap()
+    pub fn add_private_redirect_path(
+        conf: &'static Config,
+        path: &str,
+    ) -> anyhow::Result<PrivateOidcClient> {
+        Self::with_client(conf, |client| {
+            client
                 .priv_redirect_urls
-                .extend(redirect_urls_for_path(path, &conf.hostname));
-        });
-        PrivateOidcClient {
+                .extend(redirect_urls_for_path(path, &conf.hostname))
+        })?;
+        Ok(PrivateOidcClient {
             provider: TypeId::of::<T>(),
             client_id: format!("{}-private", conf.site_id),
-            private_client_name: format!("{}_client_secret", T::BeamProvider::network_name()),
+            private_client_name: Self::private_client_name(),
             get_issuer_url: T::private_issuer_url,
-        }
+        })
     }
 }
 
@@ -95,12 +113,12 @@ impl<T: OidcProvider> SyncOidc for OidcClient<T> {
         }
         self.synced = true;
         let mut secret_sync_defs = Vec::new();
-        let public_client_name = format!("{}_public_client", T::BeamProvider::network_name());
+        let public_client_name = Self::public_client_name();
         if !self.pub_redirect_paths.is_empty() {
             let public_urls = self.pub_redirect_paths.join(",");
             secret_sync_defs.push(format!("OIDC:{public_client_name}:public;{public_urls}"));
         }
-        let private_client_name = format!("{}_client_secret", T::BeamProvider::network_name());
+        let private_client_name = Self::private_client_name();
         if !self.priv_redirect_urls.is_empty() {
             let priv_urls = self.priv_redirect_urls.join(",");
             secret_sync_defs.push(format!("OIDC:{private_client_name}:private;{priv_urls}"));
@@ -120,7 +138,7 @@ impl<T: OidcProvider> SyncOidc for OidcClient<T> {
         }
         let temp_dir = std::env::temp_dir();
         let root_cert_file = temp_dir.join(format!("{}.pem", T::BeamProvider::network_name()));
-        fs::write(&root_cert_file, T::BeamProvider::root_cert())?;
+        fs::write(&root_cert_file, T::BeamProvider::root_cert().as_bytes())?;
         let mut beam_proxy_conf = Command::new("proxy");
         beam_proxy_conf
             .env("RUST_LOG", "warn")
@@ -170,11 +188,31 @@ impl<T: OidcProvider> SyncOidc for OidcClient<T> {
         Ok(())
     }
 
+    fn env_vars(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        if !self.pub_redirect_paths.is_empty() {
+            vars.push(Self::public_client_name());
+        }
+        if !self.priv_redirect_urls.is_empty() {
+            vars.push(Self::private_client_name());
+        }
+        // Same as `LocalConf::to_env`
+        vars.into_iter()
+            .map(|name| format!("OIDC_{}", name.to_uppercase()))
+            .collect()
+    }
+
     fn get_local_conf(&self) -> &'static RefCell<LocalConf> {
         self.local_conf
     }
 }
 
+/// Variables of `.env` provided by the OIDC sync of all registered clients.
+/// They may be missing if the sync failed in which case it gets retried on the next update.
+pub fn synced_env_vars() -> BTreeSet<String> {
+    OIDC_CLIENTS.with_borrow(|m| m.values().flat_map(|c| c.env_vars()).collect())
+}
+
 fn evaluate(provider: TypeId) -> &'static RefCell<LocalConf> {
     OIDC_CLIENTS.with_borrow_mut(|m| {
         let client_spec = m.get_mut(&provider).unwrap();
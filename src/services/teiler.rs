@@ -61,13 +61,13 @@ impl Service for Teiler<CcpDefault> {
     fn from_config(
         (conf, global_conf): Self::ServiceConfig,
         (fw_proxy, idm, exporter, ds): super::Deps<Self>,
-    ) -> Self {
-        Self {
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             project_t: PhantomData,
             oidc_client: OidcClient::<CcpDefault>::add_public_redirect_path(
                 global_conf,
                 &format!("/{}", Self::service_name()),
-            ),
+            )?,
             project: CcpDefault::network_name(),
             conf,
             forward_proxy_url: fw_proxy.get_url(),
@@ -80,7 +80,7 @@ impl Service for Teiler<CcpDefault> {
             oidc_user_group: format!("DKTK_CCP_{}", capitalize_first_letter(&global_conf.site_id)),
             oidc_admin_group: CcpDefault::admin_group(global_conf),
             opal_host: ds.map(|ds| ds.opal_host()),
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -1,19 +1,81 @@
-use std::{cell::RefCell, fs, path::PathBuf};
+use std::{cell::RefCell, collections::BTreeMap, fs, net::IpAddr, path::PathBuf};
 
+use anyhow::Context;
 use askama::Template;
 use bcrypt::DEFAULT_COST;
-use rcgen::CertifiedKey;
 use serde::{Deserialize, Serialize};
+use url::{Host, Url};
 
-use crate::config::LocalConf;
+use crate::{
+    config::{ConfigError, LocalConf},
+    utils::filters,
+};
 
-use super::Service;
+use super::{ForwardAuth, OidcProvider, Service, compose::ComposeFile};
 
 #[derive(Debug, Deserialize)]
 pub struct TraefikConfig {
     tls: Option<TlsConfig>,
+    acme: Option<AcmeConfig>,
+    /// Additional middlewares for the routers exposing a path keyed by the path e.g. `/ccp-localdatamanagement`
+    #[serde(default)]
+    routes: BTreeMap<String, RouteConfig>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    /// IPs or CIDR ranges that may access the path. Everyone if empty.
+    #[serde(default)]
+    ip_allow_list: Vec<String>,
+    rate_limit: Option<RateLimit>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimit {
+    /// Allowed requests per period on average
+    average: u32,
+    burst: Option<u32>,
+    /// Defaults to traefik's default of 1s
+    period: Option<String>,
+}
+
+/// Certificates for the hostname are requested from an ACME CA like Let's Encrypt
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct AcmeConfig {
+    email: String,
+    /// ACME directory URL (defaults to the Let's Encrypt production directory)
+    #[serde(default = "default_ca_server")]
+    ca_server: Url,
+    #[serde(default)]
+    challenge: AcmeChallenge,
+    /// Additional CA certificate to trust when talking to the ACME server e.g. the root of a Pebble test server
+    ca_cert: Option<PathBuf>,
+}
+
+fn default_ca_server() -> Url {
+    Url::parse("https://acme-v02.api.letsencrypt.org/directory").unwrap()
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum AcmeChallenge {
+    #[default]
+    Http,
+    /// Credentials for the provider are passed to traefik via `[overrides.traefik]`
+    Dns {
+        /// lego DNS provider name e.g. `cloudflare`
+        provider: String,
+        #[serde(default)]
+        resolvers: Vec<String>,
+    },
+}
+
+/// Name of the certificate resolver used by all routers
+const CERT_RESOLVER: &str = "acme";
+
 #[derive(Debug, Deserialize, Clone)]
 struct TlsConfig {
     cert_file: PathBuf,
@@ -24,28 +86,40 @@ struct TlsConfig {
 #[template(path = "traefik.yml")]
 pub struct Traefik {
     tls: TlsConfig,
+    acme: Option<AcmeConfig>,
     local_conf: &'static RefCell<LocalConf>,
 }
 
 impl Traefik {
-    pub fn add_basic_auth_user(&mut self, middleware_name: String) {
+    pub fn add_basic_auth_user(&mut self, middleware_name: String) -> anyhow::Result<()> {
         let mut local_conf = self.local_conf.borrow_mut();
-        let pw = crate::utils::secret_from_rng::<10>(&mut rand::rng());
-        local_conf
-            .basic_auth_users
-            .get_or_insert_default()
-            .entry(middleware_name)
-            .or_insert_with(|| {
-                if cfg!(test) {
-                    BasicAuthUser {
-                        hash: "<hash>".into(),
-                        pw: Some("test".into()),
-                    }
-                } else {
-                    let hash = bcrypt::hash(&pw, DEFAULT_COST).unwrap();
-                    BasicAuthUser { hash, pw: Some(pw) }
-                }
-            });
+        let users = local_conf.basic_auth_users.get_or_insert_default();
+        if users.contains_key(&middleware_name) {
+            return Ok(());
+        }
+        let user = if cfg!(test) {
+            BasicAuthUser {
+                hash: "<hash>".into(),
+                pw: Some("test".into()),
+            }
+        } else {
+            let pw = crate::utils::secret_from_rng::<10>(&mut rand::rng());
+            let hash = bcrypt::hash(&pw, DEFAULT_COST)
+                .with_context(|| format!("Failed to hash password for {middleware_name}"))?;
+            BasicAuthUser { hash, pw: Some(pw) }
+        };
+        users.insert(middleware_name, user);
+        Ok(())
+    }
+
+    /// Adds the basic auth user `auth` requires if any. Alternative to `add_basic_auth_user` for configurable protection.
+    pub fn add_auth(&mut self, auth: &TraefikAuth) -> anyhow::Result<()> {
+        match auth {
+            TraefikAuth::Basic {
+                middleware_and_user_name,
+            } => self.add_basic_auth_user(middleware_and_user_name.clone()),
+            TraefikAuth::Oidc { .. } => Ok(()),
+        }
     }
 }
 
@@ -53,45 +127,50 @@ impl Service for Traefik {
     type Dependencies = ();
     type ServiceConfig = &'static crate::Config;
 
-    fn from_config(conf: Self::ServiceConfig, _deps: super::Deps<Self>) -> Self {
+    fn from_config(conf: Self::ServiceConfig, _deps: super::Deps<Self>) -> anyhow::Result<Self> {
         let tls = if let Some(tls) = conf.traefik.as_ref().and_then(|t| t.tls.as_ref()) {
             // We don't check if the certs exist as they might not be mounted into the container
             tls.clone()
         } else {
             let tls_dir = conf.path.join("traefik-tls");
-            fs::create_dir_all(&tls_dir).unwrap();
+            fs::create_dir_all(&tls_dir)
+                .with_context(|| format!("Failed to create {tls_dir:?}"))?;
             let tls = TlsConfig {
                 cert_file: tls_dir.join("fullchain.pem"),
                 key_file: tls_dir.join("privkey.pem"),
             };
-            match (
-                fs::exists(&tls.cert_file).unwrap(),
-                fs::exists(&tls.key_file).unwrap(),
-            ) {
-                (false, false) => {
-                    eprintln!(
-                        "No ssl certs found for traefik {tls_dir:?}. Generating self-signed certificate"
-                    );
-                    let CertifiedKey { cert, signing_key } =
-                        rcgen::generate_simple_self_signed(vec![conf.hostname.to_string()])
-                            .unwrap();
-                    fs::write(&tls.cert_file, cert.pem()).unwrap();
-                    fs::write(&tls.key_file, signing_key.serialize_pem()).unwrap();
-                }
-                (true, false) => {
-                    panic!("fullchain.pem exists but privkey.pem does not");
-                }
-                (false, true) => {
-                    panic!("privkey.pem exists but fullchain.pem does not");
-                }
-                (true, true) => {}
-            };
+            if crate::certs::ensure_self_signed(
+                conf,
+                &tls.cert_file,
+                &tls.key_file,
+                vec![conf.hostname.to_string()],
+            )? {
+                conf.restart_required.set(true);
+            }
             tls
         };
-        Self {
+        if tls.cert_file.exists() && tls.key_file.exists() {
+            crate::certs::check_cert_pair(
+                &tls.cert_file,
+                &tls.key_file,
+                &conf.hostname,
+                conf.cert_expiry_warning_days,
+            )
+            .context("Invalid traefik certificate")?;
+        }
+        let acme = conf.traefik.as_ref().and_then(|t| t.acme.clone());
+        if acme.is_some() && !matches!(conf.hostname, Host::Domain(_)) {
+            return Err(ConfigError::new(
+                "traefik.acme",
+                "ACME certificates require hostname to be a domain name",
+            )
+            .into());
+        }
+        Ok(Self {
             tls,
+            acme,
             local_conf: &conf.local_conf,
-        }
+        })
     }
 
     fn service_name() -> String {
@@ -99,8 +178,210 @@ impl Service for Traefik {
     }
 }
 
+/// Config option of services exposed through traefik to choose how they are protected
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    #[default]
+    Basic,
+    /// SSO login through the `ForwardAuth` of the module's oidc provider
+    Oidc,
+}
+
+/// How a path exposed through traefik is protected
+#[derive(Debug)]
+pub enum TraefikAuth {
+    /// Basic auth with a generated user of the same name as the middleware
+    Basic { middleware_and_user_name: String },
+    /// SSO login through a forward auth middleware like the one of `ForwardAuth`
+    Oidc { middleware: String },
+}
+
+impl TraefikAuth {
+    /// Protection by `method` where `name` is the name of the basic auth middleware and user.
+    /// The `ForwardAuth<T>` needs to be installed for oidc.
+    pub fn new<T: OidcProvider>(method: AuthMethod, name: String) -> Self {
+        match method {
+            AuthMethod::Basic => TraefikAuth::Basic {
+                middleware_and_user_name: name,
+            },
+            AuthMethod::Oidc => TraefikAuth::Oidc {
+                middleware: ForwardAuth::<T>::middleware_name(),
+            },
+        }
+    }
+
+    pub fn middleware(&self) -> &str {
+        match self {
+            TraefikAuth::Basic {
+                middleware_and_user_name,
+            } => middleware_and_user_name,
+            TraefikAuth::Oidc { middleware } => middleware,
+        }
+    }
+}
+
+/// Makes every TLS router of the generated services request its certificate from the ACME resolver
+pub fn apply_acme(files: &mut BTreeMap<String, ComposeFile>, conf: &crate::Config) {
+    if conf.traefik.as_ref().is_none_or(|t| t.acme.is_none()) {
+        return;
+    }
+    for service in files.values_mut().flat_map(|f| f.services.values_mut()) {
+        let tls_routers: Vec<_> = service
+            .traefik_routers()
+            .into_iter()
+            .filter(|router| {
+                service
+                    .labels
+                    .0
+                    .get(&format!("traefik.http.routers.{router}.tls"))
+                    == Some(&Some("true".into()))
+            })
+            .map(str::to_string)
+            .collect();
+        for router in tls_routers {
+            let prefix = format!("traefik.http.routers.{router}.tls");
+            service
+                .labels
+                .insert(format!("{prefix}.certresolver"), CERT_RESOLVER);
+            service.labels.insert(
+                format!("{prefix}.domains[0].main"),
+                conf.hostname.to_string(),
+            );
+        }
+    }
+}
+
+/// Adds the middlewares configured under `traefik.routes` in front of the existing middlewares of the matching routers
+pub fn apply_routes(
+    files: &mut BTreeMap<String, ComposeFile>,
+    conf: &crate::Config,
+) -> anyhow::Result<()> {
+    let Some(traefik) = &conf.traefik else {
+        return Ok(());
+    };
+    for (path, route) in &traefik.routes {
+        let key = format!("traefik.routes.{path}");
+        for range in &route.ip_allow_list {
+            if !is_ip_range(range) {
+                return Err(ConfigError::new(
+                    &key,
+                    format!("{range} is not an IP address or CIDR range"),
+                )
+                .into());
+            }
+        }
+        let mut matched = false;
+        for service in files.values_mut().flat_map(|f| f.services.values_mut()) {
+            let routers: Vec<_> = service
+                .traefik_routers()
+                .into_iter()
+                .filter(|router| {
+                    service
+                        .labels
+                        .0
+                        .get(&format!("traefik.http.routers.{router}.rule"))
+                        .and_then(Option::as_deref)
+                        .is_some_and(|rule| path_prefixes(rule).contains(&path.as_str()))
+                })
+                .map(str::to_string)
+                .collect();
+            for router in routers {
+                matched = true;
+                let mut middlewares = Vec::new();
+                if !route.ip_allow_list.is_empty() {
+                    let name = format!("{router}-allowlist");
+                    service.labels.insert(
+                        format!("traefik.http.middlewares.{name}.ipallowlist.sourcerange"),
+                        route.ip_allow_list.join(","),
+                    );
+                    middlewares.push(name);
+                }
+                if let Some(rate_limit) = &route.rate_limit {
+                    let name = format!("{router}-ratelimit");
+                    let prefix = format!("traefik.http.middlewares.{name}.ratelimit");
+                    service
+                        .labels
+                        .insert(format!("{prefix}.average"), rate_limit.average.to_string());
+                    if let Some(burst) = rate_limit.burst {
+                        service
+                            .labels
+                            .insert(format!("{prefix}.burst"), burst.to_string());
+                    }
+                    if let Some(period) = &rate_limit.period {
+                        service.labels.insert(format!("{prefix}.period"), period);
+                    }
+                    middlewares.push(name);
+                }
+                let middlewares_key = format!("traefik.http.routers.{router}.middlewares");
+                if let Some(Some(existing)) = service.labels.0.get(&middlewares_key) {
+                    middlewares.push(existing.clone());
+                }
+                if !middlewares.is_empty() {
+                    service
+                        .labels
+                        .insert(middlewares_key, middlewares.join(","));
+                }
+            }
+        }
+        if !matched {
+            return Err(ConfigError::new(key, "no generated router exposes this path").into());
+        }
+    }
+    Ok(())
+}
+
+/// Paths of all `PathPrefix` matchers of a router rule
+fn path_prefixes(rule: &str) -> Vec<&str> {
+    rule.split("PathPrefix(`")
+        .skip(1)
+        .filter_map(|s| s.split_once('`').map(|(path, _)| path))
+        .collect()
+}
+
+fn is_ip_range(range: &str) -> bool {
+    let (ip, prefix) = match range.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (range, None),
+    };
+    let max_prefix = match ip.parse() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return false,
+    };
+    prefix.is_none_or(|prefix| prefix.parse::<u8>().is_ok_and(|p| p <= max_prefix))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BasicAuthUser {
     pub hash: String,
     pw: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_prefixes() {
+        assert_eq!(
+            path_prefixes("PathPrefix(`/ccp-exporter`)"),
+            ["/ccp-exporter"]
+        );
+        assert_eq!(
+            path_prefixes("Host(`example.org`) && (PathPrefix(`/a`) || PathPrefix(`/b`))"),
+            ["/a", "/b"]
+        );
+        assert!(path_prefixes("Host(`example.org`)").is_empty());
+    }
+
+    #[test]
+    fn test_is_ip_range() {
+        assert!(is_ip_range("10.0.0.0/8"));
+        assert!(is_ip_range("10.0.0.1"));
+        assert!(is_ip_range("fd00::/8"));
+        assert!(!is_ip_range("10.0.0.0/33"));
+        assert!(!is_ip_range("10.0.0.0/"));
+        assert!(!is_ip_range("example.org"));
+    }
+}
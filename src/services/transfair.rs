@@ -70,8 +70,11 @@ impl<T: BlazeProvider> Service for Transfair<T> {
     type Dependencies = (Blaze<T>,);
     type ServiceConfig = (&'static TransfairConfig, &'static crate::Config);
 
-    fn from_config((conf, global_conf): Self::ServiceConfig, (_blaze,): super::Deps<Self>) -> Self {
-        Self {
+    fn from_config(
+        (conf, global_conf): Self::ServiceConfig,
+        (_blaze,): super::Deps<Self>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             provider: PhantomData,
             conf,
             fhir_out_server: conf
@@ -81,8 +84,8 @@ impl<T: BlazeProvider> Service for Transfair<T> {
                     url: Blaze::<T>::get_url(),
                     auth: "".to_string(),
                 }),
-            trusted_ca_certs: global_conf.trusted_ca_certs(),
-        }
+            trusted_ca_certs: global_conf.trusted_ca_certs()?,
+        })
     }
 
     fn service_name() -> String {
@@ -16,15 +16,39 @@ pub fn capitalize_first_letter(s: &str) -> String {
         .collect()
 }
 
+const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                        abcdefghijklmnopqrstuvwxyz\
+                        0123456789)(*&^%#@!~";
+
 pub fn secret_from_rng<const N: usize>(rng: &mut impl rand::Rng) -> String {
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                            abcdefghijklmnopqrstuvwxyz\
-                            0123456789)(*&^%#@!~";
     (0..N)
         .map(|_| CHARSET[rng.random_range(0..CHARSET.len())] as char)
         .collect()
 }
 
+/// Derives a secret from `key` using HKDF-SHA256 with `info` as the context.
+/// Output bytes are mapped to the charset with rejection sampling to avoid modulo bias.
+pub fn secret_from_key<const N: usize>(key: &[u8; 32], info: &str) -> String {
+    let hk = hkdf::Hkdf::<sha2::Sha256>::new(None, key);
+    let limit = (256 / CHARSET.len() * CHARSET.len()) as u8;
+    let mut secret = String::with_capacity(N);
+    for block in 0_u32.. {
+        let mut okm = [0_u8; 64];
+        hk.expand(format!("{info}/{block}").as_bytes(), &mut okm)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        secret.extend(
+            okm.iter()
+                .filter(|&&b| b < limit)
+                .map(|&b| CHARSET[b as usize % CHARSET.len()] as char)
+                .take(N - secret.len()),
+        );
+        if secret.len() == N {
+            break;
+        }
+    }
+    secret
+}
+
 pub mod filters {
     use std::{fs, path::PathBuf};
 
@@ -52,19 +76,24 @@ pub mod filters {
             .downcast_ref::<Config>()
             .unwrap();
         if let Some(volume_dir) = &config.volume_dir {
-            let path = config.path.join(&volume_dir).join(name);
-            fs::create_dir_all(&path).map_err(|e| {
-                askama::Error::custom(
-                    anyhow::Error::from(e)
-                        .context(format!("Failed to create volume directory {volume_dir:?}")),
-                )
-            })?;
-            let abs_path = path.canonicalize().map_err(|e| {
-                askama::Error::custom(
-                    anyhow::Error::from(e)
-                        .context(format!("Failed to canonicalize volume {volume_dir:?}")),
-                )
-            })?;
+            let path = config.path.join(volume_dir).join(name);
+            let abs_path = if config.scratch && !path.starts_with(&config.path) {
+                // Volumes outside of the scratch directory only get created by a real update
+                path.canonicalize().unwrap_or(path)
+            } else {
+                fs::create_dir_all(&path).map_err(|e| {
+                    askama::Error::custom(
+                        anyhow::Error::from(e)
+                            .context(format!("Failed to create volume directory {volume_dir:?}")),
+                    )
+                })?;
+                path.canonicalize().map_err(|e| {
+                    askama::Error::custom(
+                        anyhow::Error::from(e)
+                            .context(format!("Failed to canonicalize volume {volume_dir:?}")),
+                    )
+                })?
+            };
             Ok(format!(
                 "{name}:\n    driver: local\n    driver_opts:\n      o: bind\n      type: none\n      device: {}",
                 abs_path.display()
@@ -75,6 +104,33 @@ pub mod filters {
     }
 }
 
+/// Serde helper for an optional 256-bit key stored as hex
+pub mod hex_key {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        key: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match key {
+            Some(key) => serializer.serialize_str(&hex::encode(key)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        let Some(hex_key) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let mut key = [0; 32];
+        hex::decode_to_slice(hex_key, &mut key)
+            .map_err(|e| serde::de::Error::custom(format!("expected 64 hex characters: {e}")))?;
+        Ok(Some(key))
+    }
+}
+
 pub mod host {
     use std::net::{Ipv4Addr, Ipv6Addr};
 
@@ -0,0 +1,215 @@
+use std::{fmt, fs, ops::Range, path::PathBuf};
+
+use anyhow::Context;
+use toml::de::{DeTable, DeValue};
+
+use crate::{
+    config::{Config, ConfigError},
+    dry_run, modules,
+    services::{ServiceMap, compose::InvalidCompose},
+};
+
+/// A problem found in config.toml
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// 1-based line and column of the problem if it can be attributed to a location
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(source: &str, span: Option<Range<usize>>, message: impl Into<String>) -> Self {
+        Self {
+            location: span.map(|span| line_col(source, span.start)),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some((line, col)) => write!(f, "config.toml:{line}:{col}: {}", self.message),
+            None => write!(f, "config.toml: {}", self.message),
+        }
+    }
+}
+
+/// Loads config.toml, installs all modules and creates their services in a scratch copy of the config directory.
+/// Returns every problem that would make an update fail without touching any file of `path`.
+pub fn validate(path: &PathBuf) -> anyhow::Result<Vec<Diagnostic>> {
+    let source = fs::read_to_string(path.join("config.toml"))
+        .with_context(|| format!("Failed to read config.toml in {path:?}"))?;
+    let (doc, errors) = DeTable::parse_recoverable(&source);
+    if !errors.is_empty() {
+        return Ok(errors
+            .iter()
+            .map(|e| Diagnostic::new(&source, e.span(), e.message()))
+            .collect());
+    }
+    // Creating services writes certificates and the like
+    let scratch = dry_run::scratch_copy(path, None)?;
+    let mut conf = match Config::load(&scratch.path().to_path_buf()) {
+        Ok(conf) => conf,
+        Err(e) => {
+            let Some(toml_err) = e.downcast_ref::<toml::de::Error>() else {
+                return Err(e);
+            };
+            return Ok(vec![Diagnostic::new(
+                &source,
+                toml_err.span(),
+                toml_err.message(),
+            )]);
+        }
+    };
+    conf.scratch = true;
+    let conf: &'static Config = Box::leak(Box::new(conf));
+    let mut services = ServiceMap::new(conf);
+    // Messages mention files in the scratch directory which only exist in the config directory for the user
+    let mut scratch_paths = vec![scratch.path().to_path_buf()];
+    scratch_paths.extend(scratch.path().canonicalize());
+    let to_diagnostics = |e: anyhow::Error| -> Vec<Diagnostic> {
+        let unscratch = |msg: String| {
+            scratch_paths.iter().fold(msg, |msg, scratch| {
+                msg.replace(&scratch.display().to_string(), &path.display().to_string())
+            })
+        };
+        if let Some(conf_err) = e.downcast_ref::<ConfigError>() {
+            vec![Diagnostic::new(
+                &source,
+                key_span(doc.get_ref(), &conf_err.key),
+                unscratch(conf_err.to_string()),
+            )]
+        } else if let Some(InvalidCompose(problems)) = e.downcast_ref::<InvalidCompose>() {
+            problems
+                .iter()
+                .map(|problem| Diagnostic::new(&source, None, unscratch(problem.clone())))
+                .collect()
+        } else {
+            vec![Diagnostic::new(&source, None, unscratch(format!("{e:#}")))]
+        }
+    };
+    let mut diagnostics = Vec::new();
+    for &module in modules::MODULES {
+        if let Err(e) = services.install_module(module) {
+            diagnostics.extend(to_diagnostics(e));
+        }
+    }
+    // Services of modules that failed to install would only add follow-up errors
+    if diagnostics.is_empty()
+        && let Err(e) = services.compose()
+    {
+        diagnostics.extend(to_diagnostics(e));
+    }
+    Ok(diagnostics)
+}
+
+/// Span of the deepest key of a dotted path like `ccp.obds2fhir` that exists in the document
+fn key_span(mut table: &DeTable, key: &str) -> Option<Range<usize>> {
+    let mut span = None;
+    for segment in key.split('.') {
+        let Some((k, v)) = table.iter().find(|(k, _)| k.get_ref() == segment) else {
+            break;
+        };
+        span = Some(k.span());
+        match v.get_ref() {
+            DeValue::Table(t) => table = t,
+            _ => break,
+        }
+    }
+    span
+}
+
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_str(config: &str) -> Vec<String> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("config.toml"), config).unwrap();
+        validate(&temp_dir.path().to_path_buf())
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        assert!(validate_str("site_id = \"dummy\"\nhostname = \"dummy.local\"\n").is_empty());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let diagnostics = validate_str("site_id = \"dummy\nhostname = \n");
+        assert!(!diagnostics.is_empty());
+        assert!(
+            diagnostics[0].starts_with("config.toml:1:"),
+            "{diagnostics:?}"
+        );
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let diagnostics =
+            validate_str("site_id = \"dummy\"\nhostname = \"dummy.local\"\nfoo = 1\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(
+            diagnostics[0].starts_with("config.toml:3:1: unknown field `foo`"),
+            "{diagnostics:?}"
+        );
+    }
+
+    #[test]
+    fn test_compose_errors() {
+        let diagnostics = validate_str(
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n\n[overrides.foo]\ntag = \"1\"\n",
+        );
+        assert_eq!(
+            diagnostics,
+            ["config.toml:4:12: overrides.foo: no generated compose service has this name"]
+        );
+    }
+
+    #[test]
+    fn test_does_not_write() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("config.toml"),
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n\n[ccp]\nexporter = {}\n",
+        )
+        .unwrap();
+        assert!(validate(&temp_dir.path().to_path_buf()).unwrap().is_empty());
+        let entries: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["config.toml"]);
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let diagnostics = validate_str(
+            "site_id = \"dummy\"\nhostname = \"dummy.local\"\n\n[ccp]\nobds2fhir = {}\n",
+        );
+        assert_eq!(
+            diagnostics,
+            [
+                "config.toml:5:1: ccp.obds2fhir: obds2fhir rest requires ccp.id_manager to be configured"
+            ]
+        );
+    }
+}